@@ -5,7 +5,7 @@ use breadx::{
     prelude::*,
     protocol::{xproto, Event},
 };
-use breadx_shm::{ShmDisplayExt, ShmImage, ShmSegment};
+use breadx_shm::{import_pixels, PixelFormat, ShmDisplayExt, ShmImage, ShmImageExt, VisualInfo};
 use std::{boxed::Box, collections::VecDeque, error::Error, io::Cursor};
 
 const EISENHOWER: &[u8] = include_bytes!("../images/eisenhower.png");
@@ -65,27 +65,20 @@ fn main() -> Result<(), Box<dyn Error>> {
     )?;
 
     // create a new image and copy the image data into it
-    let depth = conn.get_geometry_immediate(wid)?.depth;
-    let format = xproto::ImageFormat::Z_PIXMAP;
-    let len =
-        breadx_image::storage_bytes(img.width() as _, img.height() as _, depth, None, format, 32);
-    let mut ximage = ShmImage::with_display(
-        ShmSegment::attach(&mut conn, len)?,
-        img.width() as _,
-        img.height() as _,
-        format,
-        depth,
+    let mut ximage = ShmImage::create(&mut conn, wid, img.width() as _, img.height() as _)?;
+    let depth = ximage.depth();
+
+    // copy the pixels into the image, converting them to the window's visual
+    let visual = VisualInfo::root(conn.setup(), parent).ok_or("root has no visual")?;
+    import_pixels(
+        &mut ximage,
         conn.setup(),
+        &visual,
+        PixelFormat::Rgb8,
+        &img,
+        img.width() as usize * 3,
     )?;
 
-    for (x, y, pixel) in img.enumerate_pixels() {
-        // copy the pixel into the image
-        let [r, g, b] = pixel.0;
-        let channel = ((r as u32) << 16) | ((g as u32) << 8) | b as u32;
-
-        ximage.set_pixel(x as _, y as _, channel);
-    }
-
     // create a pixmap and copy the image into it
     let pixmap = conn.generate_xid()?;
     let pixmap_gc = conn.generate_xid()?;
@@ -147,10 +140,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                     img.height() as _,
                 )?;
             }
-            Event::ClientMessage(cme) => {
-                if cme.data.as_data32()[0] == wm_delete_window {
-                    break;
-                }
+            Event::ClientMessage(cme) if cme.data.as_data32()[0] == wm_delete_window => {
+                break;
             }
             _ => {}
         }
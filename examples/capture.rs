@@ -0,0 +1,32 @@
+// MIT/Apache2 License
+
+use breadx::display::DisplayConnection;
+use breadx_shm::ScreenCapturer;
+use std::{boxed::Box, error::Error};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+
+    let mut conn = DisplayConnection::connect(None)?;
+
+    // capture the root window at 30 frames per second
+    let mut capturer = ScreenCapturer::root(&conn).with_frame_rate(30.0);
+    let start = std::time::Instant::now();
+
+    for _ in 0..30 {
+        let frame = capturer.capture(&mut conn)?;
+
+        println!(
+            "frame {} at {:?}: {}x{}",
+            frame.sequence(),
+            frame.timestamp() - start,
+            frame.image().width(),
+            frame.image().height(),
+        );
+    }
+
+    // detach the buffer before we drop
+    capturer.detach(&mut conn)?;
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::VisualInfo;
+
+use breadx::{
+    display::{Display, DisplayFunctionsExt},
+    protocol::xproto::{Colormap, CreateWindowAux},
+    Result,
+};
+
+/// A 32-bit ARGB visual, along with a colormap for it.
+///
+/// Windows need a colormap of their own visual, so a translucent window
+/// can't be created without one. Create it with
+/// `ShmDisplayExt::create_argb_visual`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgbVisual {
+    /// The visual.
+    visual: VisualInfo,
+    /// A colormap for the visual.
+    colormap: Colormap,
+}
+
+impl ArgbVisual {
+    pub(crate) fn new(visual: VisualInfo, colormap: Colormap) -> Self {
+        Self { visual, colormap }
+    }
+
+    /// Get the visual.
+    pub fn visual(&self) -> &VisualInfo {
+        &self.visual
+    }
+
+    /// Get the colormap for the visual.
+    pub fn colormap(&self) -> Colormap {
+        self.colormap
+    }
+
+    /// Get the window attributes needed to create a window of this visual.
+    ///
+    /// Windows of a different depth than their parent need their own
+    /// colormap and border pixel, or the server refuses to create them.
+    /// The background is made fully transparent. Pass the visual's depth
+    /// and ID along with these into `create_window`.
+    pub fn window_aux(&self) -> CreateWindowAux {
+        CreateWindowAux::new()
+            .colormap(self.colormap)
+            .border_pixel(0)
+            .background_pixel(0)
+    }
+
+    /// Free the colormap.
+    pub fn free(self, display: &mut impl Display) -> Result<()> {
+        display.free_colormap_checked(self.colormap)
+    }
+}
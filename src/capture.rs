@@ -0,0 +1,255 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+#[cfg(feature = "xfixes")]
+use crate::CursorCompositor;
+use crate::{server_image, ImageLayout, ShmBuffer, ShmDisplayExt, ShmRecvImage};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "xfixes")]
+use breadx::protocol::Event;
+use breadx::{
+    display::{Display, DisplayFunctionsExt},
+    protocol::xproto::{Drawable, ImageFormat},
+    Result,
+};
+
+/// Continuously captures the contents of a drawable.
+///
+/// The capturer keeps a single buffer around and reuses it for every
+/// frame. If the size or depth of the drawable changes, the buffer is
+/// recreated to match.
+pub struct ScreenCapturer {
+    /// The drawable being captured.
+    drawable: Drawable,
+    /// The image that frames are captured into.
+    image: Option<ShmRecvImage>,
+    /// The time to wait between two frames.
+    interval: Option<Duration>,
+    /// The earliest time at which the next frame may be captured.
+    deadline: Option<Instant>,
+    /// The sequence number of the next frame.
+    sequence: u64,
+    /// Draws the cursor into every frame.
+    #[cfg(feature = "xfixes")]
+    cursor: Option<CursorCompositor>,
+}
+
+/// A frame captured by a `ScreenCapturer`.
+pub struct Frame<'a> {
+    /// The image containing the frame.
+    image: &'a mut ShmRecvImage,
+    /// The time at which the frame was captured.
+    timestamp: Instant,
+    /// The sequence number of the frame.
+    sequence: u64,
+}
+
+impl ScreenCapturer {
+    /// Create a new capturer for the given drawable.
+    ///
+    /// By default, frames are captured as fast as they are requested.
+    pub fn new(drawable: impl Into<Drawable>) -> Self {
+        Self {
+            drawable: drawable.into(),
+            image: None,
+            interval: None,
+            deadline: None,
+            sequence: 0,
+            #[cfg(feature = "xfixes")]
+            cursor: None,
+        }
+    }
+
+    /// Create a new capturer for the root window of the default screen.
+    pub fn root(display: &impl Display) -> Self {
+        Self::new(display.default_screen().root)
+    }
+
+    /// Limit the capturer to the given number of frames per second.
+    pub fn with_frame_rate(mut self, fps: f64) -> Self {
+        self.set_frame_rate(fps);
+        self
+    }
+
+    /// Limit the capturer to the given number of frames per second.
+    ///
+    /// A value that is not positive removes the limit.
+    pub fn set_frame_rate(&mut self, fps: f64) {
+        self.interval = if fps > 0.0 {
+            Some(Duration::from_secs_f64(fps.recip()))
+        } else {
+            None
+        };
+    }
+
+    /// Draw the cursor into every frame using the given compositor.
+    ///
+    /// The cursor is positioned relative to the root window, so this is
+    /// meant to be used when capturing the root window.
+    #[cfg(feature = "xfixes")]
+    pub fn with_cursor(mut self, cursor: CursorCompositor) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+
+    /// Handle an event, returning `true` if it was used by the capturer.
+    ///
+    /// Cursor change notifications need to be passed in here when the
+    /// cursor is being drawn.
+    #[cfg(feature = "xfixes")]
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        self.cursor
+            .as_mut()
+            .is_some_and(|cursor| cursor.handle_event(event))
+    }
+
+    /// Get the drawable being captured.
+    pub fn drawable(&self) -> Drawable {
+        self.drawable
+    }
+
+    /// Capture the next frame.
+    ///
+    /// If a frame rate is set, this sleeps until the next frame is due.
+    /// The geometry of the drawable is queried every frame, so that the
+    /// buffer can be recreated if it changes.
+    pub fn capture(&mut self, display: &mut impl Display) -> Result<Frame<'_>> {
+        // wait for the next frame to be due
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+        }
+
+        let start = Instant::now();
+        self.deadline = self.interval.map(|interval| {
+            // if we're behind, don't try to catch up
+            let next = self.deadline.unwrap_or(start) + interval;
+            next.max(start)
+        });
+
+        // make sure our buffer matches the drawable
+        let geometry = display.get_geometry_immediate(self.drawable)?;
+        self.ensure_image(display, geometry.width, geometry.height, geometry.depth)?;
+
+        let image = self.image.as_mut().unwrap();
+        display.shm_get_ximage(image, self.drawable, 0, 0, !0)?;
+
+        #[cfg(feature = "xfixes")]
+        if let Some(cursor) = self.cursor.as_mut() {
+            cursor.composite(display, image, 0, 0)?;
+        }
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        Ok(Frame {
+            image,
+            timestamp: start,
+            sequence,
+        })
+    }
+
+    /// Detach the buffer used by this capturer from the server.
+    pub fn detach(self, display: &mut impl Display) -> Result<()> {
+        match self.image {
+            Some(image) => image.into_storage().detach(display),
+            None => Ok(()),
+        }
+    }
+
+    /// Make sure the image is the right size for a frame, recreating it if
+    /// it isn't.
+    fn ensure_image(
+        &mut self,
+        display: &mut impl Display,
+        width: u16,
+        height: u16,
+        depth: u8,
+    ) -> Result<()> {
+        let matches = |image: &ShmRecvImage| {
+            image.width() == usize::from(width)
+                && image.height() == usize::from(height)
+                && image.depth() == depth
+        };
+
+        match self.image.take() {
+            Some(image) if matches(&image) => self.image = Some(image),
+            image => {
+                // throw out the old buffer
+                if let Some(image) = image {
+                    image.into_storage().detach(display)?;
+                }
+
+                self.image = Some(attach_image(display, width, height, depth)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Create a Z pixmap image backed by a new `ShmBuffer` of exactly the
+/// right size.
+pub(crate) fn attach_image(
+    display: &mut impl Display,
+    width: u16,
+    height: u16,
+    depth: u8,
+) -> Result<ShmRecvImage> {
+    attach_image_with_format(display, width, height, ImageFormat::Z_PIXMAP, depth)
+}
+
+/// Create an image in the given format backed by a new `ShmBuffer` of
+/// exactly the right size.
+pub(crate) fn attach_image_with_format(
+    display: &mut impl Display,
+    width: u16,
+    height: u16,
+    format: ImageFormat,
+    depth: u8,
+) -> Result<ShmRecvImage> {
+    let layout = ImageLayout::with_format(width, height, format, depth, display.setup())?;
+    let storage = ShmBuffer::attach(display, layout.len())?;
+
+    server_image(storage, width, height, format, depth, display.setup())
+}
+
+impl<'a> Frame<'a> {
+    /// Get the image containing the frame.
+    pub fn image(&self) -> &ShmRecvImage {
+        self.image
+    }
+
+    /// Get the image containing the frame, mutably.
+    pub fn image_mut(&mut self) -> &mut ShmRecvImage {
+        self.image
+    }
+
+    /// Get the time at which the frame was captured.
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+
+    /// Get the sequence number of the frame.
+    ///
+    /// The first frame captured has a sequence number of zero.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Take the pixel data of the frame without copying it.
+    ///
+    /// The capturer's buffer is left zeroed, and is filled again by the
+    /// next capture.
+    pub fn take_pixels(self) -> Box<[u8]> {
+        self.image.storage_mut().take_owned()
+    }
+}
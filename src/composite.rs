@@ -0,0 +1,121 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{capture::attach_image, ShmDisplayExt, ShmRecvImage};
+
+use breadx::{
+    display::{Display, DisplayExt as _, DisplayFunctionsExt},
+    protocol::{composite::Redirect, xproto::Window},
+    Result,
+};
+
+/// Captures the full contents of a window, even where it is covered by
+/// other windows.
+///
+/// The window is redirected offscreen using the Composite extension, and
+/// its backing pixmap is captured instead of the window itself. The
+/// redirection is undone when the capturer is dropped, which is why it
+/// holds on to its own handle to the display. A `&mut` reference or a
+/// `&CellDisplay` can be used as that handle.
+pub struct WindowCapturer<D: Display> {
+    /// The display the window lives on.
+    display: D,
+    /// The window being captured.
+    window: Window,
+    /// The image that the window is captured into.
+    image: Option<ShmRecvImage>,
+}
+
+impl<D: Display> WindowCapturer<D> {
+    /// Redirect the given window and start capturing it.
+    ///
+    /// The Composite extension must be available on the server, and the
+    /// window must be mapped.
+    pub fn new(mut display: D, window: Window) -> Result<Self> {
+        let version = display.composite_query_version(0, 4)?;
+        display.wait_for_reply(version)?;
+
+        // automatic redirection keeps the window visible on screen
+        display.composite_redirect_window_checked(window, Redirect::AUTOMATIC)?;
+
+        Ok(Self {
+            display,
+            window,
+            image: None,
+        })
+    }
+
+    /// Get the window being captured.
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// Get the display the window lives on.
+    pub fn display(&mut self) -> &mut D {
+        &mut self.display
+    }
+
+    /// Capture the contents of the window.
+    ///
+    /// If the window has been resized since the last capture, the buffer
+    /// is recreated. The border of the window is left out.
+    pub fn capture(&mut self) -> Result<&mut ShmRecvImage> {
+        let geometry = self.display.get_geometry_immediate(self.window)?;
+
+        let matches = self.image.as_ref().is_some_and(|image| {
+            image.width() == usize::from(geometry.width)
+                && image.height() == usize::from(geometry.height)
+                && image.depth() == geometry.depth
+        });
+
+        if !matches {
+            self.release()?;
+            self.image = Some(attach_image(
+                &mut self.display,
+                geometry.width,
+                geometry.height,
+                geometry.depth,
+            )?);
+        }
+
+        // the window gets a new pixmap every time it is mapped or resized,
+        // so the current one is named for every capture
+        let pixmap = self.display.generate_xid()?;
+        self.display
+            .composite_name_window_pixmap_checked(self.window, pixmap)?;
+
+        // the pixmap includes the border of the window
+        let border = geometry.border_width as i16;
+        let image = self.image.as_mut().unwrap();
+        let captured = self
+            .display
+            .shm_get_ximage(image, pixmap, border, border, !0);
+
+        self.display.free_pixmap(pixmap)?;
+        captured?;
+
+        Ok(image)
+    }
+
+    /// Detach the buffer.
+    fn release(&mut self) -> Result<()> {
+        if let Some(image) = self.image.take() {
+            image.into_storage().detach(&mut self.display)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: Display> Drop for WindowCapturer<D> {
+    fn drop(&mut self) {
+        // errors can't be reported from here, and the window may well be
+        // gone by now anyways
+        let _ = self.release();
+        let _ = self
+            .display
+            .composite_unredirect_window_checked(self.window, Redirect::AUTOMATIC);
+    }
+}
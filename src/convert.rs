@@ -0,0 +1,988 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{ImageLayout, VisualInfo};
+
+use breadx::{
+    protocol::xproto::{ImageOrder, Setup},
+    Result,
+};
+use breadx_image::{Format, Image};
+
+/// The layout of a tightly packed buffer of pixels.
+///
+/// Channels are copied as they are when converting between these and
+/// images, alpha is dropped if the destination has none, and missing alpha
+/// is read as opaque. The only alpha arithmetic is done by
+/// `import_premultiplied`, which multiplies the colors of straight alpha
+/// formats by their alpha on the way in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    /// Red, green, blue and alpha, one byte each.
+    Rgba8,
+    /// Blue, green, red and alpha, one byte each.
+    Bgra8,
+    /// Red, green and blue, one byte each.
+    Rgb8,
+    /// Premultiplied alpha, red, green and blue in a native-endian 32-bit
+    /// word, with alpha in the highest byte.
+    ///
+    /// This is the format used by cairo and pixman.
+    Argb32Premultiplied,
+    /// Red, green, blue and alpha, sixteen bits each in native byte order.
+    ///
+    /// This keeps the extra precision of deep color visuals.
+    Rgba16,
+    /// Red, green and blue, sixteen bits each in native byte order.
+    Rgb16,
+}
+
+impl PixelFormat {
+    /// The number of bytes each pixel takes up.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgb8 => 3,
+            Self::Rgba8 | Self::Bgra8 | Self::Argb32Premultiplied => 4,
+            Self::Rgb16 => 6,
+            Self::Rgba16 => 8,
+        }
+    }
+
+    /// Tell whether the channels of this format are sixteen bits wide.
+    pub(crate) fn is_wide(self) -> bool {
+        matches!(self, Self::Rgba16 | Self::Rgb16)
+    }
+
+    /// Read the channel at the given offset, scaled to sixteen bits.
+    pub(crate) fn channel(self, pixel: &[u8], offset: usize) -> u16 {
+        if self.is_wide() {
+            u16::from_ne_bytes([pixel[offset], pixel[offset + 1]])
+        } else {
+            u16::from(pixel[offset]) * 257
+        }
+    }
+
+    /// Write the channel at the given offset from a sixteen bit value.
+    pub(crate) fn set_channel(self, pixel: &mut [u8], offset: usize, value: u16) {
+        if self.is_wide() {
+            pixel[offset..offset + 2].copy_from_slice(&value.to_ne_bytes());
+        } else {
+            pixel[offset] = ((u32::from(value) * 255 + 32767) / 65535) as u8;
+        }
+    }
+
+    /// The positions of the channels within a pixel.
+    pub(crate) fn offsets(self) -> ByteOffsets {
+        let (rgb, alpha) = match self {
+            Self::Rgba8 => ([0, 1, 2], Some(3)),
+            Self::Bgra8 => ([2, 1, 0], Some(3)),
+            Self::Rgb8 => ([0, 1, 2], None),
+            Self::Argb32Premultiplied if cfg!(target_endian = "little") => ([2, 1, 0], Some(3)),
+            Self::Argb32Premultiplied => ([1, 2, 3], Some(0)),
+            Self::Rgba16 => ([0, 2, 4], Some(6)),
+            Self::Rgb16 => ([0, 2, 4], None),
+        };
+
+        ByteOffsets { rgb, alpha }
+    }
+}
+
+/// Convert the pixels of an image into a tightly packed buffer.
+///
+/// `visual` describes the pixels of the image, and must be a TrueColor
+/// visual. `out` must be exactly `width * height * format.bytes_per_pixel()`
+/// bytes long, or an error is returned.
+pub fn export_pixels<Storage: AsRef<[u8]> + ?Sized>(
+    image: &Image<Storage>,
+    setup: &Setup,
+    visual: &VisualInfo,
+    format: PixelFormat,
+    out: &mut [u8],
+) -> Result<()> {
+    let layout = ImageLayout::new(image, setup);
+    let codec = PixelCodec::new(&layout, visual)?;
+
+    let row_len = layout.width() * format.bytes_per_pixel();
+    if out.len() != row_len * layout.height() {
+        return Err(breadx::Error::make_msg("output buffer has the wrong size"));
+    }
+
+    if row_len == 0 {
+        return Ok(());
+    }
+
+    let data = image.storage().as_ref();
+    let whole_bytes = is_whole_bytes(image, &layout);
+
+    for (y, out) in out.chunks_exact_mut(row_len).enumerate() {
+        if !whole_bytes {
+            // odd pixel sizes aren't worth a fast path
+            for (x, out) in out.chunks_exact_mut(format.bytes_per_pixel()).enumerate() {
+                codec.decode_into(get_pixel(image, &layout, x, y), format, out);
+            }
+
+            continue;
+        }
+
+        let start = y * layout.stride();
+        let row = &data[start..start + layout.width() * codec.bytes_per_pixel];
+
+        match codec.byte_offsets {
+            Some(offsets) if !format.is_wide() => codec.decode_bytes_row(row, offsets, format, out),
+            _ => codec.decode_row(row, format, out),
+        }
+    }
+
+    Ok(())
+}
+
+/// Convert the pixels of an image into a new tightly packed buffer.
+///
+/// See `export_pixels` for details.
+pub fn export_pixels_to_vec<Storage: AsRef<[u8]> + ?Sized>(
+    image: &Image<Storage>,
+    setup: &Setup,
+    visual: &VisualInfo,
+    format: PixelFormat,
+) -> Result<Vec<u8>> {
+    let mut out = vec![0; image.width() * image.height() * format.bytes_per_pixel()];
+    export_pixels(image, setup, visual, format, &mut out)?;
+    Ok(out)
+}
+
+/// Write a buffer of pixels into an image, converting them to the layout
+/// of the image.
+///
+/// `visual` describes the pixels of the image, and must be a TrueColor
+/// visual. `src` holds one row of `width` pixels for every row of the
+/// image, with `src_stride` bytes between the starts of two rows. An error
+/// is returned if it is too small for that.
+pub fn import_pixels<Storage: AsRef<[u8]> + AsMut<[u8]> + ?Sized>(
+    image: &mut Image<Storage>,
+    setup: &Setup,
+    visual: &VisualInfo,
+    format: PixelFormat,
+    src: &[u8],
+    src_stride: usize,
+) -> Result<()> {
+    import(image, setup, visual, format, src, src_stride, false)
+}
+
+/// Write a buffer of straight alpha pixels into an image, premultiplying
+/// them on the way.
+///
+/// This is how pixels should be written into images of ARGB visuals,
+/// which the X server expects to be premultiplied. Pixels that are already
+/// premultiplied or have no alpha are written as they are. See
+/// `import_pixels` for details.
+pub fn import_premultiplied<Storage: AsRef<[u8]> + AsMut<[u8]> + ?Sized>(
+    image: &mut Image<Storage>,
+    setup: &Setup,
+    visual: &VisualInfo,
+    format: PixelFormat,
+    src: &[u8],
+    src_stride: usize,
+) -> Result<()> {
+    let premultiply = !matches!(format, PixelFormat::Argb32Premultiplied | PixelFormat::Rgb8);
+    import(image, setup, visual, format, src, src_stride, premultiply)
+}
+
+fn import<Storage: AsRef<[u8]> + AsMut<[u8]> + ?Sized>(
+    image: &mut Image<Storage>,
+    setup: &Setup,
+    visual: &VisualInfo,
+    format: PixelFormat,
+    src: &[u8],
+    src_stride: usize,
+    premultiply: bool,
+) -> Result<()> {
+    let layout = ImageLayout::new(image, setup);
+    let codec = PixelCodec::new(&layout, visual)?;
+
+    let row_len = layout.width() * format.bytes_per_pixel();
+    if row_len == 0 || layout.height() == 0 {
+        return Ok(());
+    }
+
+    if src_stride < row_len {
+        return Err(breadx::Error::make_msg("source stride is too small"));
+    }
+    if src.len() < src_stride * (layout.height() - 1) + row_len {
+        return Err(breadx::Error::make_msg("source buffer is too small"));
+    }
+
+    let whole_bytes = is_whole_bytes(image, &layout);
+    let rows = src.chunks(src_stride).take(layout.height());
+    let mut scratch = Vec::new();
+
+    for (y, src) in rows.enumerate() {
+        let mut src = &src[..row_len];
+
+        if premultiply {
+            scratch.clear();
+            scratch.extend_from_slice(src);
+            premultiply_row(&mut scratch, format);
+            src = &scratch;
+        }
+
+        if !whole_bytes {
+            for (x, src) in src.chunks_exact(format.bytes_per_pixel()).enumerate() {
+                set_pixel(image, &layout, x, y, codec.encode_from(format, src));
+            }
+
+            continue;
+        }
+
+        let start = y * layout.stride();
+        let data = image.storage_mut().as_mut();
+        let row = &mut data[start..start + layout.width() * codec.bytes_per_pixel];
+
+        match codec.byte_offsets {
+            Some(offsets) if !format.is_wide() => codec.encode_bytes_row(src, format, offsets, row),
+            _ => codec.encode_row(src, format, row),
+        }
+    }
+
+    Ok(())
+}
+
+/// Premultiply a row of straight alpha pixels in place.
+fn premultiply_row(row: &mut [u8], format: PixelFormat) {
+    let ByteOffsets { rgb, alpha } = format.offsets();
+    let alpha = match alpha {
+        Some(alpha) => alpha,
+        None => return,
+    };
+
+    for pixel in row.chunks_exact_mut(format.bytes_per_pixel()) {
+        if format.is_wide() {
+            let a = u32::from(format.channel(pixel, alpha));
+            for offset in rgb {
+                let value = u32::from(format.channel(pixel, offset));
+                format.set_channel(pixel, offset, ((value * a + 32767) / 65535) as u16);
+            }
+
+            continue;
+        }
+
+        let a = u32::from(pixel[alpha]);
+        if a == 0xFF {
+            continue;
+        }
+
+        for offset in rgb {
+            pixel[offset] = ((u32::from(pixel[offset]) * a + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Tell whether the pixels of an image can be addressed as whole bytes.
+pub(crate) fn is_whole_bytes<Storage: ?Sized>(
+    image: &Image<Storage>,
+    layout: &ImageLayout,
+) -> bool {
+    matches!(image.format(), Format::Z { .. })
+        && matches!(layout.bits_per_pixel(), 8 | 16 | 24 | 32)
+}
+
+/// Get a pixel of an image that can't be addressed as whole bytes.
+///
+/// Pixels of a single bit per plane are gathered from the planes here,
+/// since `Image::pixel` gets the plane size wrong for XY pixmaps.
+pub(crate) fn get_pixel<Storage: AsRef<[u8]> + ?Sized>(
+    image: &Image<Storage>,
+    layout: &ImageLayout,
+    x: usize,
+    y: usize,
+) -> u32 {
+    if layout.bits_per_pixel() == 1 {
+        layout.plane_pixel(image.storage().as_ref(), x, y)
+    } else {
+        image.pixel(x, y)
+    }
+}
+
+/// Set a pixel of an image that can't be addressed as whole bytes.
+pub(crate) fn set_pixel<Storage: AsRef<[u8]> + AsMut<[u8]> + ?Sized>(
+    image: &mut Image<Storage>,
+    layout: &ImageLayout,
+    x: usize,
+    y: usize,
+    pixel: u32,
+) {
+    if layout.bits_per_pixel() == 1 {
+        layout.set_plane_pixel(image.storage_mut().as_mut(), x, y, pixel);
+    } else {
+        image.set_pixel(x, y, pixel);
+    }
+}
+
+/// Describes how color channels are packed into the pixels of an image.
+pub(crate) struct PixelCodec {
+    /// The number of bytes each pixel takes up, or zero if pixels are
+    /// smaller than a byte.
+    bytes_per_pixel: usize,
+    /// The byte order of the image.
+    byte_order: ImageOrder,
+    /// The red, green, blue and alpha channels.
+    channels: [Channel; 4],
+    /// The positions of the channels within a pixel in memory, if every
+    /// channel is a whole byte.
+    byte_offsets: Option<ByteOffsets>,
+}
+
+/// The positions of the color channels within a pixel in memory.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteOffsets {
+    /// The positions of the red, green and blue bytes.
+    pub(crate) rgb: [usize; 3],
+    /// The position of the alpha byte, if there is one.
+    pub(crate) alpha: Option<usize>,
+}
+
+/// A single color channel within a pixel.
+#[derive(Clone, Copy)]
+pub(crate) struct Channel {
+    /// The position of the lowest bit of the channel.
+    pub(crate) shift: u32,
+    /// The largest value the channel can hold, or zero if it is absent.
+    pub(crate) max: u32,
+}
+
+impl PixelCodec {
+    /// Describe the pixels of an image with the given layout and visual.
+    pub(crate) fn new(layout: &ImageLayout, visual: &VisualInfo) -> Result<Self> {
+        Self::with_pixels(layout.bits_per_pixel() / 8, layout.byte_order(), visual)
+    }
+
+    /// Describe pixels of the given size and byte order.
+    fn with_pixels(
+        bytes_per_pixel: usize,
+        byte_order: ImageOrder,
+        visual: &VisualInfo,
+    ) -> Result<Self> {
+        if !visual.is_direct() {
+            return Err(breadx::Error::make_msg("visual is not a TrueColor visual"));
+        }
+
+        let [red, green, blue] = visual.masks();
+        let alpha = visual.alpha_mask();
+        let masks = [red, green, blue, alpha];
+
+        // channels that fill whole bytes can be moved around bytewise
+        let byte_offset = |mask: u32| {
+            let shift = mask.trailing_zeros() as usize;
+            if mask >> shift != 0xFF || shift % 8 != 0 || shift / 8 >= bytes_per_pixel {
+                return None;
+            }
+
+            Some(match byte_order {
+                ImageOrder::MSB_FIRST => bytes_per_pixel - 1 - shift / 8,
+                _ => shift / 8,
+            })
+        };
+
+        let byte_offsets = match (byte_offset(red), byte_offset(green), byte_offset(blue)) {
+            (Some(r), Some(g), Some(b)) if matches!(bytes_per_pixel, 3 | 4) => match alpha {
+                0 => Some(ByteOffsets {
+                    rgb: [r, g, b],
+                    alpha: None,
+                }),
+                alpha => byte_offset(alpha).map(|a| ByteOffsets {
+                    rgb: [r, g, b],
+                    alpha: Some(a),
+                }),
+            },
+            _ => None,
+        };
+
+        Ok(Self {
+            bytes_per_pixel,
+            byte_order,
+            channels: masks.map(Channel::new),
+            byte_offsets,
+        })
+    }
+
+    /// Read a pixel from the start of the given bytes.
+    fn read(&self, bytes: &[u8]) -> u32 {
+        read_pixel(&bytes[..self.bytes_per_pixel], self.byte_order)
+    }
+
+    /// Write a pixel to the start of the given bytes.
+    fn write(&self, pixel: u32, bytes: &mut [u8]) {
+        write_pixel(pixel, &mut bytes[..self.bytes_per_pixel], self.byte_order);
+    }
+
+    /// Split a pixel into its channels and write them into `out`.
+    fn decode_into(&self, pixel: u32, format: PixelFormat, out: &mut [u8]) {
+        let offsets = format.offsets();
+
+        if format.is_wide() {
+            for (channel, offset) in self.channels.iter().zip(offsets.rgb) {
+                format.set_channel(out, offset, channel.decode_wide(pixel));
+            }
+
+            if let Some(offset) = offsets.alpha {
+                format.set_channel(out, offset, self.channels[3].decode_wide(pixel));
+            }
+
+            return;
+        }
+
+        for (channel, offset) in self.channels.iter().zip(offsets.rgb) {
+            out[offset] = channel.decode(pixel);
+        }
+
+        if let Some(offset) = offsets.alpha {
+            out[offset] = self.channels[3].decode(pixel);
+        }
+    }
+
+    /// Combine the channels of a pixel in the given format.
+    fn encode_from(&self, format: PixelFormat, src: &[u8]) -> u32 {
+        let offsets = format.offsets();
+
+        if format.is_wide() {
+            let alpha = offsets
+                .alpha
+                .map_or(0xFFFF, |offset| format.channel(src, offset));
+
+            return self.channels.iter().zip(offsets.rgb).fold(
+                self.channels[3].encode_wide(alpha),
+                |pixel, (channel, offset)| pixel | channel.encode_wide(format.channel(src, offset)),
+            );
+        }
+
+        let alpha = offsets.alpha.map_or(0xFF, |offset| src[offset]);
+
+        self.channels.iter().zip(offsets.rgb).fold(
+            self.channels[3].encode(alpha),
+            |pixel, (channel, offset)| pixel | channel.encode(src[offset]),
+        )
+    }
+
+    /// Convert a row of whole-byte pixels using masks and shifts.
+    fn decode_row(&self, row: &[u8], format: PixelFormat, out: &mut [u8]) {
+        let pixels = row.chunks_exact(self.bytes_per_pixel);
+        for (pixel, out) in pixels.zip(out.chunks_exact_mut(format.bytes_per_pixel())) {
+            self.decode_into(self.read(pixel), format, out);
+        }
+    }
+
+    /// Convert a row into whole-byte pixels using masks and shifts.
+    fn encode_row(&self, src: &[u8], format: PixelFormat, row: &mut [u8]) {
+        let pixels = row.chunks_exact_mut(self.bytes_per_pixel);
+        for (pixel, src) in pixels.zip(src.chunks_exact(format.bytes_per_pixel())) {
+            self.write(self.encode_from(format, src), pixel);
+        }
+    }
+
+    /// Convert a row of pixels whose channels are whole bytes.
+    fn decode_bytes_row(
+        &self,
+        row: &[u8],
+        offsets: ByteOffsets,
+        format: PixelFormat,
+        out: &mut [u8],
+    ) {
+        // the image may already be in the requested layout
+        if self.bytes_per_pixel == format.bytes_per_pixel() && offsets == format.offsets() {
+            out.copy_from_slice(row);
+            return;
+        }
+
+        let ByteOffsets {
+            rgb: [r, g, b],
+            alpha,
+        } = format.offsets();
+        let [sr, sg, sb] = offsets.rgb;
+
+        let pixels = row.chunks_exact(self.bytes_per_pixel);
+        let out = out.chunks_exact_mut(format.bytes_per_pixel());
+
+        match (offsets.alpha, alpha) {
+            (Some(sa), Some(a)) => {
+                for (pixel, out) in pixels.zip(out) {
+                    out[r] = pixel[sr];
+                    out[g] = pixel[sg];
+                    out[b] = pixel[sb];
+                    out[a] = pixel[sa];
+                }
+            }
+            (None, Some(a)) => {
+                for (pixel, out) in pixels.zip(out) {
+                    out[r] = pixel[sr];
+                    out[g] = pixel[sg];
+                    out[b] = pixel[sb];
+                    out[a] = 0xFF;
+                }
+            }
+            (_, None) => {
+                for (pixel, out) in pixels.zip(out) {
+                    out[r] = pixel[sr];
+                    out[g] = pixel[sg];
+                    out[b] = pixel[sb];
+                }
+            }
+        }
+    }
+
+    /// Convert a row into pixels whose channels are whole bytes.
+    fn encode_bytes_row(
+        &self,
+        src: &[u8],
+        format: PixelFormat,
+        offsets: ByteOffsets,
+        row: &mut [u8],
+    ) {
+        if self.bytes_per_pixel == format.bytes_per_pixel() && offsets == format.offsets() {
+            row.copy_from_slice(src);
+            return;
+        }
+
+        let ByteOffsets {
+            rgb: [r, g, b],
+            alpha,
+        } = format.offsets();
+        let [dr, dg, db] = offsets.rgb;
+
+        let pixels = row.chunks_exact_mut(self.bytes_per_pixel);
+        let src = src.chunks_exact(format.bytes_per_pixel());
+
+        match (offsets.alpha, alpha) {
+            (Some(da), Some(a)) => {
+                for (pixel, src) in pixels.zip(src) {
+                    pixel[dr] = src[r];
+                    pixel[dg] = src[g];
+                    pixel[db] = src[b];
+                    pixel[da] = src[a];
+                }
+            }
+            (Some(da), None) => {
+                for (pixel, src) in pixels.zip(src) {
+                    pixel[dr] = src[r];
+                    pixel[dg] = src[g];
+                    pixel[db] = src[b];
+                    pixel[da] = 0xFF;
+                }
+            }
+            (None, _) => {
+                // a fourth byte that isn't alpha is padding
+                let padding = (0..self.bytes_per_pixel).find(|i| !offsets.rgb.contains(i));
+
+                for (pixel, src) in pixels.zip(src) {
+                    pixel[dr] = src[r];
+                    pixel[dg] = src[g];
+                    pixel[db] = src[b];
+
+                    if let Some(padding) = padding {
+                        pixel[padding] = 0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read a pixel that takes up exactly the given bytes.
+pub(crate) fn read_pixel(bytes: &[u8], byte_order: ImageOrder) -> u32 {
+    match byte_order {
+        ImageOrder::MSB_FIRST => bytes.iter().fold(0, |acc, &b| (acc << 8) | u32::from(b)),
+        _ => bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, &b| (acc << 8) | u32::from(b)),
+    }
+}
+
+/// Write a pixel that takes up exactly the given bytes.
+pub(crate) fn write_pixel(pixel: u32, bytes: &mut [u8], byte_order: ImageOrder) {
+    let last = bytes.len() - 1;
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let shift = match byte_order {
+            ImageOrder::MSB_FIRST => 8 * (last - i),
+            _ => 8 * i,
+        };
+
+        *byte = (pixel >> shift) as u8;
+    }
+}
+
+impl Channel {
+    /// Describe the channel covered by the given mask.
+    pub(crate) fn new(mask: u32) -> Self {
+        if mask == 0 {
+            return Self { shift: 0, max: 0 };
+        }
+
+        let shift = mask.trailing_zeros();
+        Self {
+            shift,
+            max: mask >> shift,
+        }
+    }
+
+    /// Extract the channel from a pixel, scaled to eight bits.
+    ///
+    /// Absent channels are read as fully saturated.
+    pub(crate) fn decode(self, pixel: u32) -> u8 {
+        if self.max == 0 {
+            return 0xFF;
+        }
+
+        let value = u64::from((pixel >> self.shift) & self.max);
+        let max = u64::from(self.max);
+        ((value * 255 + max / 2) / max) as u8
+    }
+
+    /// Extract the channel from a pixel, scaled to sixteen bits.
+    ///
+    /// Absent channels are read as fully saturated.
+    fn decode_wide(self, pixel: u32) -> u16 {
+        if self.max == 0 {
+            return 0xFFFF;
+        }
+
+        let value = u64::from((pixel >> self.shift) & self.max);
+        let max = u64::from(self.max);
+        ((value * 65535 + max / 2) / max) as u16
+    }
+
+    /// Scale a sixteen bit value to the channel and move it into place.
+    fn encode_wide(self, value: u16) -> u32 {
+        let max = u64::from(self.max);
+        let value = (u64::from(value) * max + 32767) / 65535;
+        (value as u32) << self.shift
+    }
+
+    /// Scale an eight bit value to the channel and move it into place.
+    ///
+    /// Absent channels are dropped.
+    fn encode(self, value: u8) -> u32 {
+        let max = u64::from(self.max);
+        let value = (u64::from(value) * max + 127) / 255;
+        (value as u32) << self.shift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_image;
+    use breadx::protocol::xproto::{self, ImageFormat, VisualClass, Visualtype};
+
+    const WIDTH: u16 = 5;
+    const HEIGHT: u16 = 3;
+
+    const ORDERS: [ImageOrder; 2] = [ImageOrder::LSB_FIRST, ImageOrder::MSB_FIRST];
+
+    const FORMATS: [PixelFormat; 6] = [
+        PixelFormat::Rgba8,
+        PixelFormat::Bgra8,
+        PixelFormat::Rgb8,
+        PixelFormat::Argb32Premultiplied,
+        PixelFormat::Rgba16,
+        PixelFormat::Rgb16,
+    ];
+
+    const RGB: [u32; 3] = [0xFF_0000, 0xFF00, 0xFF];
+
+    fn setup(byte_order: ImageOrder, depth: u8, bits_per_pixel: u8) -> Setup {
+        Setup {
+            image_byte_order: byte_order,
+            bitmap_format_bit_order: byte_order,
+            bitmap_format_scanline_unit: 32,
+            bitmap_format_scanline_pad: 32,
+            pixmap_formats: vec![xproto::Format {
+                depth,
+                bits_per_pixel,
+                scanline_pad: 32,
+            }],
+            ..Setup::default()
+        }
+    }
+
+    fn visual(depth: u8, [red_mask, green_mask, blue_mask]: [u32; 3]) -> VisualInfo {
+        VisualInfo::new(
+            depth,
+            &Visualtype {
+                class: VisualClass::TRUE_COLOR,
+                bits_per_rgb_value: 8,
+                colormap_entries: 256,
+                red_mask,
+                green_mask,
+                blue_mask,
+                ..Visualtype::default()
+            },
+        )
+    }
+
+    /// Images worth covering, along with whether their channels are whole
+    /// bytes.
+    fn cases() -> Vec<(Setup, VisualInfo, bool)> {
+        ORDERS
+            .iter()
+            .flat_map(|&order| {
+                vec![
+                    (
+                        setup(order, 16, 16),
+                        visual(16, [0xF800, 0x07E0, 0x1F]),
+                        false,
+                    ),
+                    (setup(order, 24, 32), visual(24, RGB), true),
+                    (setup(order, 32, 32), visual(32, RGB), true),
+                    (
+                        setup(order, 30, 32),
+                        visual(30, [0xFF0_0000, 0xF_F000, 0xFF0]),
+                        false,
+                    ),
+                ]
+            })
+            .collect()
+    }
+
+    /// Bytes that don't repeat too soon.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    /// Create an image filled with a pattern of pixels.
+    fn image(setup: &Setup, visual: &VisualInfo) -> Image<Vec<u8>> {
+        let depth = visual.depth();
+        let layout =
+            ImageLayout::with_format(WIDTH, HEIGHT, ImageFormat::Z_PIXMAP, depth, setup).unwrap();
+
+        server_image(
+            pattern(layout.len()),
+            WIDTH,
+            HEIGHT,
+            ImageFormat::Z_PIXMAP,
+            depth,
+            setup,
+        )
+        .unwrap()
+    }
+
+    /// Export an image one pixel at a time, through masks and shifts.
+    fn export_slowly(
+        image: &Image<Vec<u8>>,
+        setup: &Setup,
+        visual: &VisualInfo,
+        format: PixelFormat,
+    ) -> Vec<u8> {
+        let layout = ImageLayout::new(image, setup);
+        let codec = PixelCodec::new(&layout, visual).unwrap();
+        let mut out = vec![0; layout.width() * layout.height() * format.bytes_per_pixel()];
+
+        for (i, out) in out.chunks_exact_mut(format.bytes_per_pixel()).enumerate() {
+            let (x, y) = (i % layout.width(), i / layout.width());
+            let start = y * layout.stride() + x * codec.bytes_per_pixel;
+            let pixel = codec.read(&image.storage()[start..]);
+            codec.decode_into(pixel, format, out);
+        }
+
+        out
+    }
+
+    #[test]
+    fn export_fast_path_matches_masks() {
+        for (setup, visual, whole_bytes) in cases() {
+            let image = image(&setup, &visual);
+            let layout = ImageLayout::new(&image, &setup);
+            let codec = PixelCodec::new(&layout, &visual).unwrap();
+            assert_eq!(codec.byte_offsets.is_some(), whole_bytes);
+
+            for format in FORMATS {
+                assert_eq!(
+                    export_pixels_to_vec(&image, &setup, &visual, format).unwrap(),
+                    export_slowly(&image, &setup, &visual, format),
+                    "{:?} from depth {} in {:?}",
+                    format,
+                    visual.depth(),
+                    setup.image_byte_order,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn byte_rows_match_masks() {
+        // images can't have 24 bits per pixel, but servers can
+        for order in ORDERS {
+            for (bytes_per_pixel, visual) in [(3, visual(24, RGB)), (4, visual(32, RGB))] {
+                let codec = PixelCodec::with_pixels(bytes_per_pixel, order, &visual).unwrap();
+                let offsets = codec.byte_offsets.unwrap();
+                let row = pattern(bytes_per_pixel * 7);
+
+                for format in FORMATS.iter().copied().filter(|format| !format.is_wide()) {
+                    let mut fast = vec![0; format.bytes_per_pixel() * 7];
+                    let mut slow = fast.clone();
+                    codec.decode_bytes_row(&row, offsets, format, &mut fast);
+                    codec.decode_row(&row, format, &mut slow);
+
+                    assert_eq!(
+                        fast, slow,
+                        "{:?} from {} bytes in {:?}",
+                        format, bytes_per_pixel, order
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn export_reads_channels_in_byte_order() {
+        for (order, pixel) in [
+            (ImageOrder::LSB_FIRST, [0x33, 0x22, 0x11, 0x00]),
+            (ImageOrder::MSB_FIRST, [0x00, 0x11, 0x22, 0x33]),
+        ] {
+            let setup = setup(order, 24, 32);
+            let image =
+                server_image(pixel.to_vec(), 1, 1, ImageFormat::Z_PIXMAP, 24, &setup).unwrap();
+            let out =
+                export_pixels_to_vec(&image, &setup, &visual(24, RGB), PixelFormat::Rgba8).unwrap();
+
+            assert_eq!(out, [0x11, 0x22, 0x33, 0xFF]);
+        }
+    }
+
+    #[test]
+    fn export_refuses_wrong_buffer_size() {
+        let (setup, visual, _) = cases().remove(1);
+        let image = image(&setup, &visual);
+        let mut out = vec![0; 3];
+
+        assert!(export_pixels(&image, &setup, &visual, PixelFormat::Rgba8, &mut out).is_err());
+    }
+
+    #[test]
+    fn import_round_trips_through_export() {
+        let unaligned = [0xFF0_0000, 0xF_F000, 0xFF0];
+        let opaque: Vec<u8> = pattern(WIDTH as usize * HEIGHT as usize * 4)
+            .chunks(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 0xFF])
+            .collect();
+        let wide: Vec<u8> = pattern(WIDTH as usize * HEIGHT as usize * 4)
+            .into_iter()
+            .flat_map(|channel| (u16::from(channel) * 257).to_ne_bytes())
+            .collect();
+
+        for order in ORDERS {
+            let cases = [
+                (
+                    setup(order, 32, 32),
+                    visual(32, RGB),
+                    PixelFormat::Rgba8,
+                    None,
+                    true,
+                ),
+                (
+                    setup(order, 24, 32),
+                    visual(24, RGB),
+                    PixelFormat::Bgra8,
+                    Some(&opaque),
+                    true,
+                ),
+                (
+                    setup(order, 24, 32),
+                    visual(24, RGB),
+                    PixelFormat::Rgb8,
+                    None,
+                    true,
+                ),
+                (
+                    setup(order, 28, 32),
+                    visual(28, unaligned),
+                    PixelFormat::Rgb8,
+                    None,
+                    false,
+                ),
+                (
+                    setup(order, 32, 32),
+                    visual(32, RGB),
+                    PixelFormat::Rgba16,
+                    Some(&wide),
+                    false,
+                ),
+            ];
+
+            for (setup, visual, format, src, fast) in cases {
+                let len = usize::from(WIDTH) * usize::from(HEIGHT) * format.bytes_per_pixel();
+                let src = src.cloned().unwrap_or_else(|| pattern(len));
+                let mut image = image(&setup, &visual);
+
+                let layout = ImageLayout::new(&image, &setup);
+                let codec = PixelCodec::new(&layout, &visual).unwrap();
+                assert_eq!(codec.byte_offsets.is_some() && !format.is_wide(), fast);
+
+                let stride = usize::from(WIDTH) * format.bytes_per_pixel();
+                import_pixels(&mut image, &setup, &visual, format, &src, stride).unwrap();
+
+                assert_eq!(
+                    export_pixels_to_vec(&image, &setup, &visual, format).unwrap(),
+                    src,
+                    "{:?} into depth {} in {:?}",
+                    format,
+                    visual.depth(),
+                    order,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn byte_rows_encode_like_masks() {
+        for order in ORDERS {
+            for (bytes_per_pixel, visual) in [(3, visual(24, RGB)), (4, visual(32, RGB))] {
+                let codec = PixelCodec::with_pixels(bytes_per_pixel, order, &visual).unwrap();
+                let offsets = codec.byte_offsets.unwrap();
+
+                for format in FORMATS.iter().copied().filter(|format| !format.is_wide()) {
+                    let src = pattern(format.bytes_per_pixel() * 7);
+                    let mut fast = vec![0xAA; bytes_per_pixel * 7];
+                    let mut slow = fast.clone();
+                    codec.encode_bytes_row(&src, format, offsets, &mut fast);
+                    codec.encode_row(&src, format, &mut slow);
+
+                    assert_eq!(
+                        fast, slow,
+                        "{:?} into {} bytes in {:?}",
+                        format, bytes_per_pixel, order
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn import_refuses_short_buffers() {
+        let (setup, visual, _) = cases().remove(1);
+        let mut image = image(&setup, &visual);
+        let src = vec![0; usize::from(WIDTH) * usize::from(HEIGHT) * 4];
+        let stride = usize::from(WIDTH) * 4;
+
+        assert!(import_pixels(&mut image, &setup, &visual, PixelFormat::Rgba8, &src, 4).is_err());
+        assert!(import_pixels(
+            &mut image,
+            &setup,
+            &visual,
+            PixelFormat::Rgba8,
+            &src[1..],
+            stride
+        )
+        .is_err());
+        assert!(import_pixels(
+            &mut image,
+            &setup,
+            &visual,
+            PixelFormat::Rgba8,
+            &src,
+            stride
+        )
+        .is_ok());
+    }
+}
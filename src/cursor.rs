@@ -0,0 +1,183 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{ShmRecvImage, VisualInfo};
+
+use breadx::{
+    display::{Display, DisplayExt as _, DisplayFunctionsExt},
+    protocol::{
+        xfixes::{self, CursorNotifyMask},
+        xproto::Window,
+        Event,
+    },
+    Result,
+};
+
+/// Draws the cursor into captured images.
+///
+/// SHM GetImage never includes the cursor, so it has to be fetched with
+/// XFIXES and blended in by hand. The cursor image is cached until the
+/// server reports that the cursor has changed; in the meantime, only the
+/// pointer position is queried.
+pub struct CursorCompositor {
+    /// The root window of the screen the cursor is on.
+    root: Window,
+    /// The color masks of the root visual.
+    masks: [u32; 3],
+    /// The cached cursor image.
+    cursor: Option<CachedCursor>,
+}
+
+/// A cursor image fetched from the server.
+struct CachedCursor {
+    /// The width of the cursor.
+    width: u16,
+    /// The height of the cursor.
+    height: u16,
+    /// The X coordinate of the cursor's hotspot.
+    xhot: u16,
+    /// The Y coordinate of the cursor's hotspot.
+    yhot: u16,
+    /// The serial number of the cursor.
+    serial: u32,
+    /// The premultiplied ARGB pixels of the cursor.
+    pixels: Vec<u32>,
+}
+
+impl CursorCompositor {
+    /// Create a new compositor for the cursor on the given root window.
+    ///
+    /// The XFIXES extension must be available on the server. This selects
+    /// cursor notifications on the root window; pass them into
+    /// `handle_event` so that the cached cursor image is kept up to date.
+    pub fn new(display: &mut impl Display, root: Window) -> Result<Self> {
+        let version = display.xfixes_query_version(5, 0)?;
+        display.wait_for_reply(version)?;
+        display.xfixes_select_cursor_input_checked(root, CursorNotifyMask::DISPLAY_CURSOR)?;
+
+        let masks = VisualInfo::root(display.setup(), root)
+            .map(|visual| visual.masks())
+            .ok_or_else(|| breadx::Error::make_msg("root window has no known visual"))?;
+
+        Ok(Self {
+            root,
+            masks,
+            cursor: None,
+        })
+    }
+
+    /// Handle an event, returning `true` if it invalidated the cached
+    /// cursor image.
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        match (event, &self.cursor) {
+            (Event::XfixesCursorNotify(notify), Some(cursor))
+                if notify.cursor_serial != cursor.serial =>
+            {
+                self.cursor = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget the cached cursor image, so that it is fetched again.
+    pub fn invalidate(&mut self) {
+        self.cursor = None;
+    }
+
+    /// Blend the cursor into an image.
+    ///
+    /// `origin_x` and `origin_y` are the coordinates of the image's top
+    /// left corner, relative to the root window.
+    pub fn composite(
+        &mut self,
+        display: &mut impl Display,
+        image: &mut ShmRecvImage,
+        origin_x: i16,
+        origin_y: i16,
+    ) -> Result<()> {
+        // the cursor image comes with its position, so only query the
+        // pointer if we already have it
+        let (x, y) = match self.cursor {
+            Some(_) => {
+                let pointer = display.query_pointer_immediate(self.root)?;
+                (pointer.root_x, pointer.root_y)
+            }
+            None => {
+                let reply = display.xfixes_get_cursor_image_immediate()?;
+                let position = (reply.x, reply.y);
+                self.cursor = Some(CachedCursor::from(reply));
+                position
+            }
+        };
+
+        let cursor = self.cursor.as_ref().unwrap();
+        let left = i32::from(x) - i32::from(cursor.xhot) - i32::from(origin_x);
+        let top = i32::from(y) - i32::from(cursor.yhot) - i32::from(origin_y);
+
+        for cy in 0..i32::from(cursor.height) {
+            let dy = top + cy;
+            if dy < 0 || dy >= image.height() as i32 {
+                continue;
+            }
+
+            for cx in 0..i32::from(cursor.width) {
+                let dx = left + cx;
+                if dx < 0 || dx >= image.width() as i32 {
+                    continue;
+                }
+
+                let src = cursor.pixels[(cy * i32::from(cursor.width) + cx) as usize];
+                if src >> 24 == 0 {
+                    continue;
+                }
+
+                let (dx, dy) = (dx as usize, dy as usize);
+                let dst = image.pixel(dx, dy);
+                image.set_pixel(dx, dy, blend(src, dst, &self.masks));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl From<xfixes::GetCursorImageReply> for CachedCursor {
+    fn from(reply: xfixes::GetCursorImageReply) -> Self {
+        Self {
+            width: reply.width,
+            height: reply.height,
+            xhot: reply.xhot,
+            yhot: reply.yhot,
+            serial: reply.cursor_serial,
+            pixels: reply.cursor_image,
+        }
+    }
+}
+
+/// Blend a premultiplied ARGB pixel over a pixel using the given masks.
+fn blend(src: u32, dst: u32, masks: &[u32; 3]) -> u32 {
+    let alpha = src >> 24;
+    let mut out = dst & !(masks[0] | masks[1] | masks[2]);
+
+    for (i, &mask) in masks.iter().enumerate() {
+        if mask == 0 {
+            continue;
+        }
+
+        let shift = mask.trailing_zeros();
+        let max = mask >> shift;
+
+        // scale the destination channel to eight bits, blend it, then
+        // scale it back
+        let src = (src >> (16 - 8 * i)) & 0xFF;
+        let dst = ((dst & mask) >> shift) * 255 / max;
+        let blended = (src + dst * (255 - alpha) / 255).min(255);
+
+        out |= ((blended * max + 127) / 255) << shift;
+    }
+
+    out
+}
@@ -0,0 +1,315 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{capture::attach_image, ImageLayout, ShmDisplayExt, ShmRecvImage};
+use std::{ops::Range, time::Instant};
+
+use breadx::{
+    display::{Display, DisplayExt as _, DisplayFunctionsExt},
+    protocol::{
+        damage::{self, ReportLevel},
+        xfixes,
+        xproto::{Drawable, ImageFormat, Rectangle},
+    },
+    Result,
+};
+
+/// Captures the contents of a drawable, only transferring the parts of
+/// it that have changed.
+///
+/// The DAMAGE extension is used to track which parts of the drawable
+/// have changed since the last frame. Only the rows covering those parts
+/// are captured, directly into their place in the buffer.
+///
+/// Whole rows are captured rather than the changed rectangles themselves,
+/// because SHM GetImage always writes scanlines as wide as the captured
+/// area. A narrower rectangle would not line up with the scanlines of the
+/// buffer, and would have to be captured somewhere else and copied into
+/// place. The changed rectangles are still reported exactly.
+///
+/// If the drawable changes size, the buffer is recreated and the entire
+/// drawable is captured again.
+pub struct DamageCapturer {
+    /// The drawable being captured.
+    drawable: Drawable,
+    /// The damage object tracking the drawable.
+    damage: damage::Damage,
+    /// The region that damage is moved into every frame.
+    parts: xfixes::Region,
+    /// The image that frames are captured into.
+    image: ShmRecvImage,
+    /// The layout of `image`.
+    layout: ImageLayout,
+    /// The sequence number of the next frame.
+    sequence: u64,
+    /// Whether the next frame needs to capture the entire drawable.
+    full: bool,
+}
+
+/// A frame captured by a `DamageCapturer`.
+pub struct DamageFrame<'a> {
+    /// The image containing the frame.
+    image: &'a mut ShmRecvImage,
+    /// The rectangles that changed since the last frame.
+    changed: Vec<Rectangle>,
+    /// The time at which the frame was captured.
+    timestamp: Instant,
+    /// The sequence number of the frame.
+    sequence: u64,
+}
+
+impl DamageCapturer {
+    /// Start capturing the given drawable.
+    ///
+    /// The DAMAGE and XFIXES extensions must be available on the server.
+    pub fn new(display: &mut impl Display, drawable: impl Into<Drawable>) -> Result<Self> {
+        let drawable = drawable.into();
+
+        // both extensions need to know which version we speak
+        let xfixes_version = display.xfixes_query_version(5, 0)?;
+        let damage_version = display.damage_query_version(1, 1)?;
+        display.wait_for_reply(xfixes_version)?;
+        display.wait_for_reply(damage_version)?;
+
+        let geometry = display.get_geometry_immediate(drawable)?;
+        let image = attach_image(display, geometry.width, geometry.height, geometry.depth)?;
+        let layout = ImageLayout::new(&image, display.setup());
+
+        let (damage, parts) = match Self::create_objects(display, drawable) {
+            Ok(objects) => objects,
+            Err(err) => {
+                image.into_storage().detach(display)?;
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            drawable,
+            damage,
+            parts,
+            image,
+            layout,
+            sequence: 0,
+            full: true,
+        })
+    }
+
+    /// Create the damage object and the region that damage is moved into.
+    ///
+    /// The damage object is destroyed again if the region can't be created.
+    fn create_objects(
+        display: &mut impl Display,
+        drawable: Drawable,
+    ) -> Result<(damage::Damage, xfixes::Region)> {
+        let damage = display.generate_xid()?;
+        display.damage_create_checked(damage, drawable, ReportLevel::NON_EMPTY)?;
+
+        let parts = display.generate_xid().and_then(|parts| {
+            display
+                .xfixes_create_region_checked(parts, [])
+                .map(|()| parts)
+        });
+
+        match parts {
+            Ok(parts) => Ok((damage, parts)),
+            Err(err) => {
+                display.damage_destroy_checked(damage)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Get the drawable being captured.
+    pub fn drawable(&self) -> Drawable {
+        self.drawable
+    }
+
+    /// Capture the parts of the drawable that changed since the last frame.
+    ///
+    /// The first frame, and the first frame after the drawable changes
+    /// size, captures the entire drawable.
+    pub fn capture(&mut self, display: &mut impl Display) -> Result<DamageFrame<'_>> {
+        let timestamp = Instant::now();
+
+        // move the accumulated damage into our region, so that anything
+        // drawn from now on is reported next frame
+        display.damage_subtract_checked(self.damage, xfixes::RegionEnum::NONE, self.parts)?;
+
+        // capturing with the old size fails once the drawable shrinks
+        let geometry = display.get_geometry_immediate(self.drawable)?;
+        self.ensure_image(display, geometry.width, geometry.height, geometry.depth)?;
+
+        let changed = if self.full {
+            display.shm_get_ximage(&mut self.image, self.drawable, 0, 0, !0)?;
+            self.full = false;
+
+            vec![Rectangle {
+                x: 0,
+                y: 0,
+                width: self.image.width() as _,
+                height: self.image.height() as _,
+            }]
+        } else {
+            let region = display.xfixes_fetch_region_immediate(self.parts)?;
+            let changed: Vec<_> = region
+                .rectangles
+                .into_iter()
+                .filter_map(|rect| clip(rect, &self.layout))
+                .collect();
+
+            self.capture_rows(display, &changed)?;
+            changed
+        };
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        Ok(DamageFrame {
+            image: &mut self.image,
+            changed,
+            timestamp,
+            sequence,
+        })
+    }
+
+    /// Stop capturing, destroying the server-side objects and detaching
+    /// the buffer.
+    pub fn destroy(self, display: &mut impl Display) -> Result<()> {
+        display.damage_destroy_checked(self.damage)?;
+        display.xfixes_destroy_region_checked(self.parts)?;
+        self.image.into_storage().detach(display)
+    }
+
+    /// Make sure the image matches the drawable, recreating it if it
+    /// doesn't.
+    ///
+    /// The new buffer is attached before the old one is detached, so the
+    /// capturer keeps working if it can't be.
+    fn ensure_image(
+        &mut self,
+        display: &mut impl Display,
+        width: u16,
+        height: u16,
+        depth: u8,
+    ) -> Result<()> {
+        if self.image.width() == usize::from(width)
+            && self.image.height() == usize::from(height)
+            && self.image.depth() == depth
+        {
+            return Ok(());
+        }
+
+        let image = attach_image(display, width, height, depth)?;
+        self.layout = ImageLayout::new(&image, display.setup());
+        let old = std::mem::replace(&mut self.image, image);
+        self.full = true;
+
+        old.into_storage().detach(display)
+    }
+
+    /// Capture every row that one of the rectangles covers.
+    ///
+    /// Full-width strips share the stride of the buffer, so they can be
+    /// written straight into their place in it.
+    fn capture_rows(&mut self, display: &mut impl Display, changed: &[Rectangle]) -> Result<()> {
+        let strips = merge_rows(changed);
+        let seg_id = self.image.storage().seg_id;
+
+        // send all of the requests before waiting on any of them
+        let cookies = strips
+            .iter()
+            .map(|rows| {
+                display.shm_get_image(
+                    self.drawable,
+                    0,
+                    rows.start as _,
+                    self.image.width() as _,
+                    rows.len() as _,
+                    !0,
+                    ImageFormat::Z_PIXMAP.into(),
+                    seg_id,
+                    (rows.start * self.layout.stride()) as _,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for cookie in cookies {
+            display.wait_for_reply(cookie)?;
+        }
+
+        for rows in strips {
+            self.image.storage_mut().repopulate_rows(&self.layout, rows);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> DamageFrame<'a> {
+    /// Get the image containing the frame.
+    pub fn image(&self) -> &ShmRecvImage {
+        self.image
+    }
+
+    /// Get the image containing the frame, mutably.
+    pub fn image_mut(&mut self) -> &mut ShmRecvImage {
+        self.image
+    }
+
+    /// Get the rectangles that changed since the last frame.
+    pub fn changed(&self) -> &[Rectangle] {
+        &self.changed
+    }
+
+    /// Get the time at which the frame was captured.
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+
+    /// Get the sequence number of the frame.
+    ///
+    /// The first frame captured has a sequence number of zero.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// Clip a rectangle to the bounds of the image.
+fn clip(rect: Rectangle, layout: &ImageLayout) -> Option<Rectangle> {
+    let x = i32::from(rect.x).max(0);
+    let y = i32::from(rect.y).max(0);
+    let right = (i32::from(rect.x) + i32::from(rect.width)).min(layout.width() as i32);
+    let bottom = (i32::from(rect.y) + i32::from(rect.height)).min(layout.height() as i32);
+
+    if x < right && y < bottom {
+        Some(Rectangle {
+            x: x as _,
+            y: y as _,
+            width: (right - x) as _,
+            height: (bottom - y) as _,
+        })
+    } else {
+        None
+    }
+}
+
+/// Merge the rows covered by the rectangles into disjoint ranges.
+fn merge_rows(rects: &[Rectangle]) -> Vec<Range<usize>> {
+    let mut rows: Vec<Range<usize>> = rects
+        .iter()
+        .map(|rect| rect.y as usize..rect.y as usize + rect.height as usize)
+        .collect();
+    rows.sort_unstable_by_key(|rows| rows.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(rows.len());
+    for range in rows {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
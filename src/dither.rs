@@ -0,0 +1,345 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{
+    convert::{is_whole_bytes, set_pixel, write_pixel, Channel, PixelFormat},
+    is_x11_error, ImageLayout, VisualInfo,
+};
+
+use breadx::{
+    display::{Display, DisplayExt as _, DisplayFunctionsExt},
+    protocol::{
+        xproto::{Colormap, Setup},
+        ErrorKind,
+    },
+    Result,
+};
+use breadx_image::Image;
+
+/// How colors are reduced to the few that a low-depth visual can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dither {
+    /// Round every pixel to the closest color.
+    None,
+    /// Add a fixed 8x8 threshold pattern to every pixel before rounding.
+    ///
+    /// This is fast and stable between frames.
+    Ordered,
+    /// Spread the rounding error of every pixel onto its neighbors.
+    ///
+    /// This looks best for still images.
+    FloydSteinberg,
+}
+
+/// A color cube allocated in a colormap, for visuals whose pixels index
+/// into a colormap.
+///
+/// Colors that can't be allocated are replaced with the closest ones that
+/// could be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    /// The number of levels of each channel in the cube.
+    levels: u8,
+    /// The pixel of every entry of the cube.
+    pixels: Vec<u32>,
+    /// The color that every entry actually got, which may differ from the
+    /// requested one.
+    colors: Vec<[u8; 3]>,
+    /// The pixels that were allocated and need to be freed.
+    allocated: Vec<u32>,
+}
+
+/// The bayer matrix used for ordered dithering.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+impl Palette {
+    /// Allocate a color cube with the given number of levels for each
+    /// channel in a colormap of the given visual.
+    ///
+    /// Six levels, for 216 colors, leave room for other clients in an
+    /// 8-bit colormap. The cube can't have more colors than the colormap.
+    pub fn allocate(
+        display: &mut impl Display,
+        colormap: Colormap,
+        visual: &VisualInfo,
+        levels: u8,
+    ) -> Result<Self> {
+        if levels < 2 {
+            return Err(breadx::Error::make_msg(
+                "a palette needs at least two levels",
+            ));
+        }
+
+        if usize::from(levels).pow(3) > usize::from(visual.colormap_entries()) {
+            return Err(breadx::Error::make_msg(
+                "palette has more colors than the colormap",
+            ));
+        }
+
+        let levels_usize = usize::from(levels);
+        let level = |i: usize| (i * 0xFFFF / (levels_usize - 1)) as u16;
+
+        // send all of the requests before waiting on any of them
+        let mut cookies = Vec::with_capacity(levels_usize.pow(3));
+        let mut failure = None;
+        for i in 0..levels_usize.pow(3) {
+            let (r, g, b) = cube_levels(i, levels_usize);
+            match display.alloc_color(colormap, level(r), level(g), level(b)) {
+                Ok(cookie) => cookies.push(cookie),
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                }
+            }
+        }
+
+        // on failure, keep going so that every color we got can be freed
+        let mut entries = Vec::with_capacity(cookies.len());
+        for cookie in cookies {
+            match display.wait_for_reply(cookie) {
+                Ok(reply) => {
+                    let color = [reply.red, reply.green, reply.blue].map(|c| (c >> 8) as u8);
+                    entries.push(Some((reply.pixel, color)));
+                }
+                // the colormap is full
+                Err(err) if is_x11_error(&err, ErrorKind::Alloc) => entries.push(None),
+                Err(err) => {
+                    failure.get_or_insert(err);
+                    entries.push(None);
+                }
+            }
+        }
+
+        let allocated: Vec<_> = entries.iter().flatten().copied().collect();
+        if let Some(err) = failure {
+            let pixels: Vec<_> = allocated.iter().map(|&(pixel, _)| pixel).collect();
+            display.free_colors_checked(colormap, 0, &pixels)?;
+            return Err(err);
+        }
+
+        if allocated.is_empty() {
+            return Err(breadx::Error::make_msg("no colors could be allocated"));
+        }
+
+        // fill the holes with the closest colors we did get
+        let (pixels, colors) = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                entry.unwrap_or_else(|| {
+                    let (r, g, b) = cube_levels(i, levels_usize);
+                    let wanted = [r, g, b].map(|c| (level(c) >> 8) as u8);
+                    closest(&allocated, wanted)
+                })
+            })
+            .unzip();
+
+        Ok(Self {
+            levels,
+            pixels,
+            colors,
+            allocated: allocated.into_iter().map(|(pixel, _)| pixel).collect(),
+        })
+    }
+
+    /// Get the number of levels of each channel in the cube.
+    pub fn levels(&self) -> u8 {
+        self.levels
+    }
+
+    /// Find the pixel for a color.
+    pub fn pixel(&self, color: [u8; 3]) -> u32 {
+        let max = u32::from(self.levels) - 1;
+        let [r, g, b] = color.map(|c| ((u32::from(c) * max + 127) / 255) as usize);
+        let levels = usize::from(self.levels);
+
+        self.pixels[(r * levels + g) * levels + b]
+    }
+
+    /// Free the colors allocated for this palette.
+    pub fn free(self, display: &mut impl Display, colormap: Colormap) -> Result<()> {
+        display.free_colors_checked(colormap, 0, &self.allocated)
+    }
+}
+
+/// Write a buffer of pixels into an image of any depth, dithering them
+/// if the visual has fewer colors than the source.
+///
+/// TrueColor visuals are written directly, which is how 15 and 16-bit
+/// visuals should be written. Every other visual needs a `palette`
+/// allocated in the colormap the image is shown with. Alpha is ignored.
+/// See `import_pixels` for the layout of `src`.
+pub fn import_dithered<Storage: AsRef<[u8]> + AsMut<[u8]> + ?Sized>(
+    image: &mut Image<Storage>,
+    setup: &Setup,
+    visual: &VisualInfo,
+    palette: Option<&Palette>,
+    format: PixelFormat,
+    src: &[u8],
+    src_stride: usize,
+    dither: Dither,
+) -> Result<()> {
+    let layout = ImageLayout::new(image, setup);
+    let quantizer = match (visual.is_direct(), palette) {
+        (true, _) => Quantizer::Direct(visual.masks().map(Channel::new)),
+        (false, Some(palette)) => Quantizer::Palette(palette),
+        (false, None) => {
+            return Err(breadx::Error::make_msg(
+                "visual needs a palette to be written to",
+            ))
+        }
+    };
+
+    let (width, height) = (layout.width(), layout.height());
+    let row_len = width * format.bytes_per_pixel();
+    if row_len == 0 || height == 0 {
+        return Ok(());
+    }
+
+    assert!(src_stride >= row_len, "source stride is too small");
+    assert!(
+        src.len() >= src_stride * (height - 1) + row_len,
+        "source buffer is too small"
+    );
+
+    let offsets = format.offsets().rgb;
+    let maxes = quantizer.maxes();
+    let whole_bytes = is_whole_bytes(image, &layout);
+    let bytes_per_pixel = layout.bits_per_pixel() / 8;
+
+    // the errors carried into the current and the next row
+    let mut errors = vec![[0i32; 3]; width + 2];
+    let mut next_errors = vec![[0i32; 3]; width + 2];
+
+    for (y, src) in src.chunks(src_stride).take(height).enumerate() {
+        for (x, src) in src[..row_len]
+            .chunks_exact(format.bytes_per_pixel())
+            .enumerate()
+        {
+            let mut levels = [0; 3];
+            let mut wanted = [0; 3];
+
+            for c in 0..3 {
+                let max = maxes[c] as i32;
+                let mut value = i32::from(format.channel(src, offsets[c]) >> 8);
+
+                match dither {
+                    Dither::None => {}
+                    Dither::Ordered if max > 0 => {
+                        let threshold = 2 * i32::from(BAYER[y % 8][x % 8]) + 1 - 64;
+                        value += threshold * 255 / (128 * max);
+                    }
+                    Dither::Ordered => {}
+                    Dither::FloydSteinberg => value += errors[x + 1][c] / 16,
+                }
+
+                let value = value.clamp(0, 255);
+                wanted[c] = value;
+                levels[c] = if max > 0 {
+                    (value * max + 127) / 255
+                } else {
+                    0
+                };
+            }
+
+            let (pixel, actual) = quantizer.pixel(levels.map(|level| level as u32));
+
+            if dither == Dither::FloydSteinberg {
+                for c in 0..3 {
+                    let error = wanted[c] - i32::from(actual[c]);
+                    errors[x + 2][c] += error * 7;
+                    next_errors[x][c] += error * 3;
+                    next_errors[x + 1][c] += error * 5;
+                    next_errors[x + 2][c] += error;
+                }
+            }
+
+            if whole_bytes {
+                let start = y * layout.stride() + x * bytes_per_pixel;
+                let data = &mut image.storage_mut().as_mut()[start..start + bytes_per_pixel];
+                write_pixel(pixel, data, layout.byte_order());
+            } else {
+                set_pixel(image, &layout, x, y, pixel);
+            }
+        }
+
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.iter_mut().for_each(|error| *error = [0; 3]);
+    }
+
+    Ok(())
+}
+
+/// Maps channel levels to pixels.
+enum Quantizer<'a> {
+    /// The levels are packed into the pixel using masks.
+    Direct([Channel; 3]),
+    /// The levels index into a color cube.
+    Palette(&'a Palette),
+}
+
+impl<'a> Quantizer<'a> {
+    /// The highest level of every channel.
+    fn maxes(&self) -> [u32; 3] {
+        match self {
+            Self::Direct(channels) => channels.map(|channel| channel.max),
+            Self::Palette(palette) => [u32::from(palette.levels) - 1; 3],
+        }
+    }
+
+    /// Find the pixel for the given levels, as well as the color it shows.
+    fn pixel(&self, levels: [u32; 3]) -> (u32, [u8; 3]) {
+        match self {
+            Self::Direct(channels) => {
+                let mut pixel = 0;
+                let mut color = [0; 3];
+
+                for (c, channel) in channels.iter().enumerate() {
+                    pixel |= levels[c] << channel.shift;
+                    color[c] = channel.decode(pixel);
+                }
+
+                (pixel, color)
+            }
+            Self::Palette(palette) => {
+                let count = u32::from(palette.levels);
+                let index = ((levels[0] * count + levels[1]) * count + levels[2]) as usize;
+                (palette.pixels[index], palette.colors[index])
+            }
+        }
+    }
+}
+
+/// Split an index into a color cube into its levels.
+fn cube_levels(index: usize, levels: usize) -> (usize, usize, usize) {
+    (
+        index / (levels * levels),
+        (index / levels) % levels,
+        index % levels,
+    )
+}
+
+/// Find the closest of the given colors to a color.
+fn closest(colors: &[(u32, [u8; 3])], wanted: [u8; 3]) -> (u32, [u8; 3]) {
+    *colors
+        .iter()
+        .min_by_key(|(_, color)| {
+            color
+                .iter()
+                .zip(wanted)
+                .map(|(&a, b)| (i32::from(a) - i32::from(b)).pow(2))
+                .sum::<i32>()
+        })
+        .unwrap()
+}
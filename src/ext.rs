@@ -0,0 +1,462 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{
+    capture::attach_image_with_format, server_image, ImageLayout, Pixel, Rows, RowsMut, ShmImage,
+    ShmRecvImage, ShmSegment, VisualInfo,
+};
+
+use breadx::{
+    display::{Display, DisplayFunctionsExt},
+    protocol::xproto::{Drawable, ImageFormat, Setup},
+    Result,
+};
+use breadx_image::Image;
+
+#[cfg(feature = "image")]
+use crate::convert::{export_pixels_to_vec, import_pixels, import_premultiplied, PixelFormat};
+#[cfg(feature = "image")]
+use crate::is_x11_error;
+#[cfg(feature = "image")]
+use breadx::protocol::ErrorKind;
+#[cfg(feature = "image")]
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
+#[cfg(feature = "rayon")]
+use rayon::vec::IntoIter as ParIter;
+#[cfg(feature = "image")]
+use std::convert::TryFrom;
+
+/// What an image is being created for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageTarget {
+    /// The image is put onto or captured from this drawable.
+    Drawable(Drawable),
+    /// The image holds pixels of this visual.
+    Visual(VisualInfo),
+}
+
+/// Extension methods for `ShmImage`.
+pub trait ShmImageExt: Sized {
+    /// Create an image of the given size for the given target, backed by
+    /// a segment of exactly the right size.
+    ///
+    /// The depth is taken from the target, and the bits per pixel and the
+    /// scanline pad from the setup of the display.
+    fn create(
+        display: &mut impl Display,
+        target: impl Into<ImageTarget>,
+        width: u16,
+        height: u16,
+    ) -> Result<Self> {
+        Self::create_with_format(display, target, width, height, ImageFormat::Z_PIXMAP)
+    }
+
+    /// Create an image in the given format, backed by a segment of exactly
+    /// the right size.
+    ///
+    /// XY pixmaps get one plane for every bit of the depth of the target,
+    /// and XY bitmaps always have a single plane. Use `ImageLayout` to get
+    /// at the individual planes.
+    fn create_with_format(
+        display: &mut impl Display,
+        target: impl Into<ImageTarget>,
+        width: u16,
+        height: u16,
+        format: ImageFormat,
+    ) -> Result<Self>;
+
+    /// View the rows of the image as slices of whole pixels.
+    ///
+    /// The segment is page aligned, so any pixel type as wide as the
+    /// pixels of the image can be used. See `ImageLayout::rows` for the
+    /// rest of the requirements on the pixel type.
+    fn rows<P: Pixel>(&self, setup: &Setup) -> Result<Rows<'_, P>>;
+
+    /// View the rows of the image as mutable slices of whole pixels.
+    ///
+    /// See `rows` for the requirements on the pixel type.
+    fn rows_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<RowsMut<'_, P>>;
+
+    /// View the entire image as a single slice of pixels.
+    ///
+    /// This only works if scanlines have no padding at their ends. See
+    /// `rows` for the requirements on the pixel type.
+    fn as_pixels<P: Pixel>(&self, setup: &Setup) -> Result<&[P]>;
+
+    /// View the entire image as a single mutable slice of pixels.
+    ///
+    /// See `as_pixels` for when this works.
+    fn as_pixels_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<&mut [P]>;
+
+    /// View the rows of the image as mutable slices of whole pixels, to be
+    /// processed in parallel.
+    ///
+    /// See `rows` for the requirements on the pixel type.
+    #[cfg(feature = "rayon")]
+    fn par_rows_mut<P: Pixel + Send>(&mut self, setup: &Setup) -> Result<ParIter<&mut [P]>>;
+
+    /// Split the bytes of the image into chunks of the given number of
+    /// scanlines, to be processed in parallel.
+    ///
+    /// See `ImageLayout::par_chunks_mut` for how the image is split.
+    #[cfg(feature = "rayon")]
+    fn par_chunks_mut(&mut self, setup: &Setup, rows: usize) -> Result<ParIter<&mut [u8]>>;
+
+    /// Create an image that can be put onto the given drawable, containing
+    /// the pixels of a `DynamicImage`.
+    ///
+    /// A segment of exactly the right size is attached, and the pixels are
+    /// converted into the visual of the drawable. Alpha is premultiplied
+    /// if the visual has an alpha channel.
+    #[cfg(feature = "image")]
+    fn from_image(
+        image: &DynamicImage,
+        display: &mut impl Display,
+        drawable: impl Into<Drawable>,
+    ) -> Result<Self>;
+}
+
+/// Extension methods for `ShmRecvImage`.
+pub trait ShmRecvImageExt: Sized {
+    /// Create an image of the given size for the given target, backed by
+    /// a buffer of exactly the right size.
+    ///
+    /// The depth is taken from the target, and the bits per pixel and the
+    /// scanline pad from the setup of the display.
+    fn create(
+        display: &mut impl Display,
+        target: impl Into<ImageTarget>,
+        width: u16,
+        height: u16,
+    ) -> Result<Self> {
+        Self::create_with_format(display, target, width, height, ImageFormat::Z_PIXMAP)
+    }
+
+    /// Create an image in the given format, backed by a buffer of exactly
+    /// the right size.
+    ///
+    /// XY pixmaps get one plane for every bit of the depth of the target,
+    /// and XY bitmaps always have a single plane. Use `ImageLayout` to get
+    /// at the individual planes.
+    fn create_with_format(
+        display: &mut impl Display,
+        target: impl Into<ImageTarget>,
+        width: u16,
+        height: u16,
+        format: ImageFormat,
+    ) -> Result<Self>;
+
+    /// View the rows of the image as slices of whole pixels.
+    ///
+    /// The buffer is aligned to four bytes, so any pixel type as wide as
+    /// the pixels of the image can be used. See `ImageLayout::rows` for
+    /// the rest of the requirements on the pixel type.
+    fn rows<P: Pixel>(&self, setup: &Setup) -> Result<Rows<'_, P>>;
+
+    /// View the rows of the image as mutable slices of whole pixels.
+    ///
+    /// See `rows` for the requirements on the pixel type.
+    fn rows_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<RowsMut<'_, P>>;
+
+    /// View the entire image as a single slice of pixels.
+    ///
+    /// This only works if scanlines have no padding at their ends. See
+    /// `rows` for the requirements on the pixel type.
+    fn as_pixels<P: Pixel>(&self, setup: &Setup) -> Result<&[P]>;
+
+    /// View the entire image as a single mutable slice of pixels.
+    ///
+    /// See `as_pixels` for when this works.
+    fn as_pixels_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<&mut [P]>;
+
+    /// View the rows of the image as mutable slices of whole pixels, to be
+    /// processed in parallel.
+    ///
+    /// See `rows` for the requirements on the pixel type.
+    #[cfg(feature = "rayon")]
+    fn par_rows_mut<P: Pixel + Send>(&mut self, setup: &Setup) -> Result<ParIter<&mut [P]>>;
+
+    /// Split the bytes of the image into chunks of the given number of
+    /// scanlines, to be processed in parallel.
+    ///
+    /// See `ImageLayout::par_chunks_mut` for how the image is split.
+    #[cfg(feature = "rayon")]
+    fn par_chunks_mut(&mut self, setup: &Setup, rows: usize) -> Result<ParIter<&mut [u8]>>;
+
+    /// Convert the pixels of a captured image into an `RgbaImage`.
+    ///
+    /// `visual` describes the pixels of the captured image.
+    #[cfg(feature = "image")]
+    fn to_rgba_image(&self, setup: &Setup, visual: &VisualInfo) -> Result<RgbaImage>;
+
+    /// Convert the pixels of a captured image into an image with sixteen
+    /// bits per channel, keeping the precision of deep color visuals.
+    ///
+    /// `visual` describes the pixels of the captured image.
+    #[cfg(feature = "image")]
+    fn to_rgba16_image(
+        &self,
+        setup: &Setup,
+        visual: &VisualInfo,
+    ) -> Result<ImageBuffer<Rgba<u16>, Vec<u16>>>;
+}
+
+// the typed views of both image types are the same, apart from how their
+// storage is aligned
+
+fn image_rows<'a, S: AsRef<[u8]>, P: Pixel>(
+    image: &'a Image<S>,
+    setup: &Setup,
+) -> Result<Rows<'a, P>> {
+    ImageLayout::new(image, setup).rows(image.storage().as_ref())
+}
+
+fn image_rows_mut<'a, S: AsMut<[u8]>, P: Pixel>(
+    image: &'a mut Image<S>,
+    setup: &Setup,
+) -> Result<RowsMut<'a, P>> {
+    ImageLayout::new(image, setup).rows_mut(image.storage_mut().as_mut())
+}
+
+fn image_pixels<'a, S: AsRef<[u8]>, P: Pixel>(
+    image: &'a Image<S>,
+    setup: &Setup,
+) -> Result<&'a [P]> {
+    ImageLayout::new(image, setup).as_pixels(image.storage().as_ref())
+}
+
+fn image_pixels_mut<'a, S: AsMut<[u8]>, P: Pixel>(
+    image: &'a mut Image<S>,
+    setup: &Setup,
+) -> Result<&'a mut [P]> {
+    ImageLayout::new(image, setup).as_pixels_mut(image.storage_mut().as_mut())
+}
+
+#[cfg(feature = "rayon")]
+fn image_par_rows_mut<'a, S: AsMut<[u8]>, P: Pixel + Send>(
+    image: &'a mut Image<S>,
+    setup: &Setup,
+) -> Result<ParIter<&'a mut [P]>> {
+    ImageLayout::new(image, setup).par_rows_mut(image.storage_mut().as_mut())
+}
+
+#[cfg(feature = "rayon")]
+fn image_par_chunks_mut<'a, S: AsMut<[u8]>>(
+    image: &'a mut Image<S>,
+    setup: &Setup,
+    rows: usize,
+) -> Result<ParIter<&'a mut [u8]>> {
+    ImageLayout::new(image, setup).par_chunks_mut(image.storage_mut().as_mut(), rows)
+}
+
+impl ImageTarget {
+    /// Find the depth of images for this target.
+    fn depth(self, display: &mut impl Display) -> Result<u8> {
+        match self {
+            Self::Drawable(drawable) => Ok(display.get_geometry_immediate(drawable)?.depth),
+            Self::Visual(visual) => Ok(visual.depth()),
+        }
+    }
+}
+
+/// Windows and pixmaps convert into drawables, so they can be passed in
+/// directly. Note that visual IDs are plain integers as well; wrap them in
+/// a `VisualInfo` instead.
+impl From<Drawable> for ImageTarget {
+    fn from(drawable: Drawable) -> Self {
+        Self::Drawable(drawable)
+    }
+}
+
+impl From<VisualInfo> for ImageTarget {
+    fn from(visual: VisualInfo) -> Self {
+        Self::Visual(visual)
+    }
+}
+
+impl ShmImageExt for ShmImage {
+    fn create_with_format(
+        display: &mut impl Display,
+        target: impl Into<ImageTarget>,
+        width: u16,
+        height: u16,
+        format: ImageFormat,
+    ) -> Result<Self> {
+        let depth = target.into().depth(display)?;
+        let layout = ImageLayout::with_format(width, height, format, depth, display.setup())?;
+        let segment = ShmSegment::attach(display, layout.len())?;
+
+        server_image(segment, width, height, format, depth, display.setup())
+    }
+
+    fn rows<P: Pixel>(&self, setup: &Setup) -> Result<Rows<'_, P>> {
+        image_rows(self, setup)
+    }
+
+    fn rows_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<RowsMut<'_, P>> {
+        image_rows_mut(self, setup)
+    }
+
+    fn as_pixels<P: Pixel>(&self, setup: &Setup) -> Result<&[P]> {
+        image_pixels(self, setup)
+    }
+
+    fn as_pixels_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<&mut [P]> {
+        image_pixels_mut(self, setup)
+    }
+
+    #[cfg(feature = "rayon")]
+    fn par_rows_mut<P: Pixel + Send>(&mut self, setup: &Setup) -> Result<ParIter<&mut [P]>> {
+        image_par_rows_mut(self, setup)
+    }
+
+    #[cfg(feature = "rayon")]
+    fn par_chunks_mut(&mut self, setup: &Setup, rows: usize) -> Result<ParIter<&mut [u8]>> {
+        image_par_chunks_mut(self, setup, rows)
+    }
+
+    #[cfg(feature = "image")]
+    fn from_image(
+        image: &DynamicImage,
+        display: &mut impl Display,
+        drawable: impl Into<Drawable>,
+    ) -> Result<Self> {
+        let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => return Err(breadx::Error::make_msg("image is too large for X11")),
+        };
+
+        let visual = drawable_visual(display, drawable.into())?;
+        let mut ximage = Self::create(display, visual, width, height)?;
+
+        // avoid converting the image if it's already in a format we take
+        let converted;
+        let converted16;
+        let (pixels, format) = match image {
+            DynamicImage::ImageRgb8(image) => (image.as_raw(), PixelFormat::Rgb8),
+            DynamicImage::ImageRgba8(image) => (image.as_raw(), PixelFormat::Rgba8),
+            // keep the precision of sixteen bit images for deep color visuals
+            DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)
+                if visual.bits_per_rgb() > 8 =>
+            {
+                converted16 = image
+                    .to_rgba16()
+                    .into_raw()
+                    .into_iter()
+                    .flat_map(u16::to_ne_bytes)
+                    .collect::<Vec<_>>();
+                (&converted16, PixelFormat::Rgba16)
+            }
+            image => {
+                converted = image.to_rgba8();
+                (converted.as_raw(), PixelFormat::Rgba8)
+            }
+        };
+
+        // visuals with alpha are composited with premultiplied colors
+        let import = if visual.alpha_mask() != 0 {
+            import_premultiplied
+        } else {
+            import_pixels
+        };
+
+        let stride = usize::from(width) * format.bytes_per_pixel();
+        let imported = import(
+            &mut ximage,
+            display.setup(),
+            &visual,
+            format,
+            pixels,
+            stride,
+        );
+
+        match imported {
+            Ok(()) => Ok(ximage),
+            Err(err) => {
+                ximage.into_storage().detach(display)?;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Find the visual of a drawable.
+///
+/// Pixmaps have no visual of their own, so the visual that windows of
+/// their depth use is taken for them instead.
+#[cfg(feature = "image")]
+fn drawable_visual(display: &mut impl Display, drawable: Drawable) -> Result<VisualInfo> {
+    let geometry = display.get_geometry_immediate(drawable)?;
+
+    let visual = match display.get_window_attributes_immediate(drawable) {
+        Ok(attributes) => VisualInfo::find(display.setup(), attributes.visual),
+        Err(err) if is_x11_error(&err, ErrorKind::Window) => {
+            VisualInfo::for_depth(display.setup(), geometry.root, geometry.depth)
+        }
+        Err(err) => return Err(err),
+    };
+
+    visual.ok_or_else(|| breadx::Error::make_msg("drawable has no known visual"))
+}
+
+impl ShmRecvImageExt for ShmRecvImage {
+    fn create_with_format(
+        display: &mut impl Display,
+        target: impl Into<ImageTarget>,
+        width: u16,
+        height: u16,
+        format: ImageFormat,
+    ) -> Result<Self> {
+        let depth = target.into().depth(display)?;
+        attach_image_with_format(display, width, height, format, depth)
+    }
+
+    fn rows<P: Pixel>(&self, setup: &Setup) -> Result<Rows<'_, P>> {
+        image_rows(self, setup)
+    }
+
+    fn rows_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<RowsMut<'_, P>> {
+        image_rows_mut(self, setup)
+    }
+
+    fn as_pixels<P: Pixel>(&self, setup: &Setup) -> Result<&[P]> {
+        image_pixels(self, setup)
+    }
+
+    fn as_pixels_mut<P: Pixel>(&mut self, setup: &Setup) -> Result<&mut [P]> {
+        image_pixels_mut(self, setup)
+    }
+
+    #[cfg(feature = "rayon")]
+    fn par_rows_mut<P: Pixel + Send>(&mut self, setup: &Setup) -> Result<ParIter<&mut [P]>> {
+        image_par_rows_mut(self, setup)
+    }
+
+    #[cfg(feature = "rayon")]
+    fn par_chunks_mut(&mut self, setup: &Setup, rows: usize) -> Result<ParIter<&mut [u8]>> {
+        image_par_chunks_mut(self, setup, rows)
+    }
+
+    #[cfg(feature = "image")]
+    fn to_rgba_image(&self, setup: &Setup, visual: &VisualInfo) -> Result<RgbaImage> {
+        let pixels = export_pixels_to_vec(self, setup, visual, PixelFormat::Rgba8)?;
+        Ok(RgbaImage::from_raw(self.width() as _, self.height() as _, pixels).unwrap())
+    }
+
+    #[cfg(feature = "image")]
+    fn to_rgba16_image(
+        &self,
+        setup: &Setup,
+        visual: &VisualInfo,
+    ) -> Result<ImageBuffer<Rgba<u16>, Vec<u16>>> {
+        let pixels = export_pixels_to_vec(self, setup, visual, PixelFormat::Rgba16)?;
+        let pixels = pixels
+            .chunks_exact(2)
+            .map(|channel| u16::from_ne_bytes([channel[0], channel[1]]))
+            .collect();
+
+        Ok(ImageBuffer::from_raw(self.width() as _, self.height() as _, pixels).unwrap())
+    }
+}
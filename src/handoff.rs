@@ -0,0 +1,111 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Create a pair of ends for passing images between a thread that renders
+/// into them and a thread that presents them.
+///
+/// The given images start out on the render end. The render end acquires
+/// an image, draws into it and submits it; the present end receives it,
+/// puts it onto a drawable and releases it back once the server is done
+/// reading it. Any image type can be passed, such as `ShmImage`,
+/// `TrackedShmImage` or `ShmStagedImage`.
+pub fn handoff<T: Send>(images: impl IntoIterator<Item = T>) -> (RenderEnd<T>, PresentEnd<T>) {
+    let (free_tx, free_rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    for image in images {
+        free_tx.send(image).ok();
+    }
+
+    (
+        RenderEnd {
+            free: free_rx,
+            ready: ready_tx,
+        },
+        PresentEnd {
+            ready: ready_rx,
+            free: free_tx,
+        },
+    )
+}
+
+/// The end of a handoff that renders into images.
+pub struct RenderEnd<T> {
+    /// Images released by the present end.
+    free: Receiver<T>,
+    /// Images submitted to the present end.
+    ready: Sender<T>,
+}
+
+/// The end of a handoff that presents images.
+pub struct PresentEnd<T> {
+    /// Images submitted by the render end.
+    ready: Receiver<T>,
+    /// Images released to the render end.
+    free: Sender<T>,
+}
+
+impl<T> RenderEnd<T> {
+    /// Wait for an image to render into.
+    ///
+    /// Returns `None` if the present end has been dropped.
+    pub fn acquire(&self) -> Option<T> {
+        self.free.recv().ok()
+    }
+
+    /// Get an image to render into, if one is free.
+    pub fn try_acquire(&self) -> Option<T> {
+        self.free.try_recv().ok()
+    }
+
+    /// Submit a rendered image to the present end.
+    ///
+    /// The image is given back if the present end has been dropped.
+    pub fn submit(&self, image: T) -> Result<(), T> {
+        self.ready.send(image).map_err(|err| err.0)
+    }
+}
+
+impl<T> PresentEnd<T> {
+    /// Wait for the next rendered image.
+    ///
+    /// Returns `None` once the render end has been dropped and every image
+    /// submitted by it has been received.
+    pub fn recv(&self) -> Option<T> {
+        self.ready.recv().ok()
+    }
+
+    /// Get the next rendered image, if there is one.
+    pub fn try_recv(&self) -> Option<T> {
+        self.ready.try_recv().ok()
+    }
+
+    /// Get the most recently rendered image, if there is one.
+    ///
+    /// Older images that were never presented are released back to the
+    /// render end.
+    pub fn latest(&self) -> Option<T> {
+        let mut latest = None;
+
+        while let Ok(image) = self.ready.try_recv() {
+            if let Some(older) = latest.replace(image) {
+                self.release(older).ok();
+            }
+        }
+
+        latest
+    }
+
+    /// Give an image back to the render end.
+    ///
+    /// The server must be done reading the image first, for instance once
+    /// `shm_put_ximage` or `shm_put_ximage_neh_checked` has returned. The
+    /// image is given back if the render end has been dropped.
+    pub fn release(&self, image: T) -> Result<(), T> {
+        self.free.send(image).map_err(|err| err.0)
+    }
+}
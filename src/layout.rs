@@ -0,0 +1,823 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use breadx::protocol::xproto::{ImageFormat, ImageOrder, Setup};
+use breadx_image::{BitsPerPixel, Format, Image, Quantum, XyFormatType};
+use std::{
+    convert::TryFrom,
+    ops::Range,
+    slice::{Chunks, ChunksMut},
+};
+
+#[cfg(feature = "rayon")]
+use rayon::{prelude::*, vec::IntoIter as ParIter};
+
+/// Describes how the pixels of an image are laid out in its storage.
+///
+/// `breadx_image` keeps the scanline pad and the byte order of an image
+/// to itself. This structure recomputes them the same way that
+/// `Image::with_display` picks them, so it is accurate for any image
+/// created through that function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageLayout {
+    /// The width of the image, in pixels.
+    width: usize,
+    /// The height of the image, in pixels.
+    height: usize,
+    /// The depth of the image.
+    depth: u8,
+    /// The number of bits each pixel takes up in a scanline.
+    ///
+    /// This is always 1 for XY images.
+    bits_per_pixel: usize,
+    /// The number of bits to skip at the start of every scanline.
+    left_pad: usize,
+    /// The number of bytes between the starts of two scanlines.
+    stride: usize,
+    /// The number of planes in the image.
+    ///
+    /// This is 1 for Z images and the depth for XY images.
+    planes: usize,
+    /// The number of bits in the units that bitmap scanlines are made of.
+    scanline_unit: usize,
+    /// The byte order used by the image.
+    byte_order: ImageOrder,
+    /// The bit order used by the image.
+    bit_order: ImageOrder,
+}
+
+impl ImageLayout {
+    /// Compute the layout of an image created using the given setup.
+    pub fn new<Storage: ?Sized>(image: &Image<Storage>, setup: &Setup) -> Self {
+        let width = image.width();
+        let height = image.height();
+
+        match *image.format() {
+            Format::Z {
+                depth,
+                bits_per_pixel,
+            } => {
+                let bits_per_pixel = bits_per_pixel as usize;
+                let scanline_pad = setup
+                    .pixmap_formats
+                    .iter()
+                    .find(|format| format.depth == depth)
+                    .map_or(setup.bitmap_format_scanline_pad, |format| {
+                        format.scanline_pad
+                    });
+
+                Self {
+                    width,
+                    height,
+                    depth,
+                    bits_per_pixel,
+                    left_pad: 0,
+                    stride: pad_to(width * bits_per_pixel, scanline_pad.into()) / 8,
+                    planes: 1,
+                    scanline_unit: setup.bitmap_format_scanline_unit.into(),
+                    byte_order: setup.image_byte_order,
+                    bit_order: setup.bitmap_format_bit_order,
+                }
+            }
+            Format::Xy {
+                format,
+                quantum,
+                bit_order,
+                left_pad,
+            } => {
+                let depth = match format {
+                    XyFormatType::Bitmap => 1,
+                    XyFormatType::Pixmap { depth } => depth,
+                };
+                let left_pad = left_pad as usize;
+                let scanline_pad = setup.bitmap_format_scanline_pad.into();
+
+                Self {
+                    width,
+                    height,
+                    depth,
+                    bits_per_pixel: 1,
+                    left_pad,
+                    stride: pad_to(width + left_pad, scanline_pad) / 8,
+                    planes: depth.into(),
+                    scanline_unit: quantum as usize,
+                    byte_order: setup.image_byte_order,
+                    bit_order,
+                }
+            }
+        }
+    }
+
+    /// Compute the layout of an image that `server_image` would create
+    /// with the given parameters.
+    pub fn with_format(
+        width: u16,
+        height: u16,
+        format: ImageFormat,
+        depth: u8,
+        setup: &Setup,
+    ) -> breadx::Result<Self> {
+        let image = server_image(&[][..], width, height, format, depth, setup)?;
+        Ok(Self::new(&image, setup))
+    }
+
+    /// The width of the image, in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The height of the image, in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The depth of the image.
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// The number of bits each pixel takes up in a scanline.
+    pub fn bits_per_pixel(&self) -> usize {
+        self.bits_per_pixel
+    }
+
+    /// The number of bytes between the starts of two scanlines.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// The number of planes in the image.
+    pub fn planes(&self) -> usize {
+        self.planes
+    }
+
+    /// The number of bits in the units that bitmap scanlines are made of.
+    pub fn scanline_unit(&self) -> usize {
+        self.scanline_unit
+    }
+
+    /// The byte order used by the image.
+    pub fn byte_order(&self) -> ImageOrder {
+        self.byte_order
+    }
+
+    /// The bit order used by the image.
+    pub fn bit_order(&self) -> ImageOrder {
+        self.bit_order
+    }
+
+    /// The number of bytes in a single plane.
+    pub fn plane_len(&self) -> usize {
+        self.stride * self.height
+    }
+
+    /// The number of bytes needed to store the entire image.
+    pub fn len(&self) -> usize {
+        self.plane_len() * self.planes
+    }
+
+    /// Tell whether the image contains no bytes at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The byte range covered by the given plane.
+    ///
+    /// Planes are stored from the most significant one down, so plane 0
+    /// holds the highest bit of every pixel. When an XY image is captured
+    /// with a plane mask, only the planes in the mask are transferred, and
+    /// they are packed together from plane 0 on.
+    pub fn plane_range(&self, plane: usize) -> Range<usize> {
+        let start = plane * self.plane_len();
+        start..start + self.plane_len()
+    }
+
+    /// Find the byte holding the bit of a pixel in the given plane, along
+    /// with the mask of that bit in the byte.
+    ///
+    /// This is only meaningful for images with one bit per pixel in every
+    /// plane, which are XY images and Z images of depth 1.
+    pub fn bit_position(&self, x: usize, y: usize, plane: usize) -> (usize, u8) {
+        debug_assert_eq!(
+            self.bits_per_pixel, 1,
+            "image has more than one bit per pixel"
+        );
+
+        let index = x + self.left_pad;
+        let (unit, bit) = (index / self.scanline_unit, index % self.scanline_unit);
+
+        // the bit order decides which end of the unit the leftmost pixel is
+        // at, and the byte order decides how the unit is laid out in memory
+        let bit = match self.bit_order {
+            ImageOrder::MSB_FIRST => self.scanline_unit - 1 - bit,
+            _ => bit,
+        };
+        let unit_bytes = self.scanline_unit / 8;
+        let byte = match self.byte_order {
+            ImageOrder::MSB_FIRST => unit_bytes - 1 - bit / 8,
+            _ => bit / 8,
+        };
+
+        let offset = plane * self.plane_len() + y * self.stride + unit * unit_bytes + byte;
+        (offset, 1 << (bit % 8))
+    }
+
+    /// Get the bit of a pixel in the given plane.
+    pub fn bit(&self, data: &[u8], x: usize, y: usize, plane: usize) -> bool {
+        let (offset, mask) = self.bit_position(x, y, plane);
+        data[offset] & mask != 0
+    }
+
+    /// Set the bit of a pixel in the given plane.
+    pub fn set_bit(&self, data: &mut [u8], x: usize, y: usize, plane: usize, value: bool) {
+        let (offset, mask) = self.bit_position(x, y, plane);
+
+        if value {
+            data[offset] |= mask;
+        } else {
+            data[offset] &= !mask;
+        }
+    }
+
+    /// Get a pixel by gathering its bits from every plane.
+    pub fn plane_pixel(&self, data: &[u8], x: usize, y: usize) -> u32 {
+        (0..self.planes).fold(0, |pixel, plane| {
+            (pixel << 1) | u32::from(self.bit(data, x, y, plane))
+        })
+    }
+
+    /// Set a pixel by scattering its bits into every plane.
+    pub fn set_plane_pixel(&self, data: &mut [u8], x: usize, y: usize, pixel: u32) {
+        for plane in 0..self.planes {
+            let bit = self.planes - 1 - plane;
+            self.set_bit(data, x, y, plane, (pixel >> bit) & 1 != 0);
+        }
+    }
+
+    /// The byte range covered by the given rows in every plane.
+    ///
+    /// Rows past the bottom of the image are ignored.
+    pub fn row_ranges(&self, rows: Range<usize>) -> impl Iterator<Item = Range<usize>> + '_ {
+        let start = rows.start.min(self.height) * self.stride;
+        let end = rows.end.min(self.height) * self.stride;
+
+        (0..self.planes)
+            .map(move |plane| plane * self.plane_len())
+            .filter(move |_| start < end)
+            .map(move |base| base + start..base + end)
+    }
+
+    /// The byte ranges covered by the given rectangle in every plane.
+    ///
+    /// The rectangle is clipped to the bounds of the image. Each range
+    /// covers one scanline of the rectangle; bytes that are only partially
+    /// covered are included.
+    pub fn rect_ranges(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> impl Iterator<Item = Range<usize>> + '_ {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        let x = x.min(x_end);
+        let y = y.min(y_end);
+
+        let start = (x * self.bits_per_pixel + self.left_pad) / 8;
+        let end = pad_to(x_end * self.bits_per_pixel + self.left_pad, 8) / 8;
+        let empty = start >= end;
+
+        (0..self.planes)
+            .flat_map(move |plane| {
+                (y..y_end).map(move |row| plane * self.plane_len() + row * self.stride)
+            })
+            .filter(move |_| !empty)
+            .map(move |base| base + start..base + end)
+    }
+
+    /// View the rows of a Z image as slices of whole pixels.
+    ///
+    /// Every slice is `width` pixels long, leaving out the padding at the
+    /// end of each scanline. The pixel type must be as wide as the pixels
+    /// of the image, and pixels wider than a byte must be stored in the
+    /// byte order of this machine.
+    pub fn rows<'a, P: Pixel>(&self, data: &'a [u8]) -> breadx::Result<Rows<'a, P>> {
+        let stride = (self.stride / P::SIZE).max(1);
+        let pixels = cast::<P>(self.pixel_bytes::<P>(data)?)?;
+
+        Ok(Rows {
+            chunks: pixels.chunks(stride),
+            width: self.width,
+        })
+    }
+
+    /// View the rows of a Z image as mutable slices of whole pixels.
+    ///
+    /// See `rows` for the requirements on the pixel type.
+    pub fn rows_mut<'a, P: Pixel>(&self, data: &'a mut [u8]) -> breadx::Result<RowsMut<'a, P>> {
+        let stride = (self.stride / P::SIZE).max(1);
+        let len = self.pixel_bytes::<P>(data)?.len();
+        let pixels = cast_mut::<P>(&mut data[..len])?;
+
+        Ok(RowsMut {
+            chunks: pixels.chunks_mut(stride),
+            width: self.width,
+        })
+    }
+
+    /// View an entire Z image as a single slice of pixels.
+    ///
+    /// This only works if scanlines have no padding at their ends. See
+    /// `rows` for the requirements on the pixel type.
+    pub fn as_pixels<'a, P: Pixel>(&self, data: &'a [u8]) -> breadx::Result<&'a [P]> {
+        self.check_unpadded::<P>()?;
+        cast(self.pixel_bytes::<P>(data)?)
+    }
+
+    /// View an entire Z image as a single mutable slice of pixels.
+    ///
+    /// See `as_pixels` for when this works.
+    pub fn as_pixels_mut<'a, P: Pixel>(&self, data: &'a mut [u8]) -> breadx::Result<&'a mut [P]> {
+        self.check_unpadded::<P>()?;
+        let len = self.pixel_bytes::<P>(data)?.len();
+        cast_mut(&mut data[..len])
+    }
+
+    /// View the rows of a Z image as mutable slices of whole pixels, to be
+    /// processed in parallel.
+    ///
+    /// See `rows` for the requirements on the pixel type.
+    #[cfg(feature = "rayon")]
+    pub fn par_rows_mut<'a, P: Pixel + Send>(
+        &self,
+        data: &'a mut [u8],
+    ) -> breadx::Result<ParIter<&'a mut [P]>> {
+        let rows: Vec<_> = self.rows_mut(data)?.collect();
+        Ok(rows.into_par_iter())
+    }
+
+    /// Split the bytes of an image into chunks of the given number of
+    /// scanlines, to be processed in parallel.
+    ///
+    /// Chunks include the padding at the end of every scanline, and never
+    /// span two planes. The last chunk of a plane may be shorter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rows` is zero.
+    #[cfg(feature = "rayon")]
+    pub fn par_chunks_mut<'a>(
+        &self,
+        data: &'a mut [u8],
+        rows: usize,
+    ) -> breadx::Result<ParIter<&'a mut [u8]>> {
+        assert!(rows > 0, "chunks must have at least one row");
+
+        let plane_len = self.plane_len().max(1);
+        let chunk_len = (rows * self.stride).max(1);
+        let data = data
+            .get_mut(..self.len())
+            .ok_or_else(|| breadx::Error::make_msg("buffer is too small for the image"))?;
+
+        let chunks: Vec<_> = data
+            .chunks_mut(plane_len)
+            .flat_map(|plane| plane.chunks_mut(chunk_len))
+            .collect();
+        Ok(chunks.into_par_iter())
+    }
+
+    /// Make sure that pixels of the given type can be used to address the
+    /// image, and get the bytes covered by its scanlines.
+    fn pixel_bytes<'a, P: Pixel>(&self, data: &'a [u8]) -> breadx::Result<&'a [u8]> {
+        if self.planes != 1 || self.bits_per_pixel != P::SIZE * 8 {
+            return Err(breadx::Error::make_msg(
+                "pixel type does not match the pixels of the image",
+            ));
+        }
+
+        let native = if cfg!(target_endian = "little") {
+            ImageOrder::LSB_FIRST
+        } else {
+            ImageOrder::MSB_FIRST
+        };
+        if P::SIZE > 1 && self.byte_order != native {
+            return Err(breadx::Error::make_msg(
+                "image is not in the byte order of this machine",
+            ));
+        }
+
+        if self.stride % P::SIZE != 0 {
+            return Err(breadx::Error::make_msg(
+                "scanlines don't hold a whole number of pixels",
+            ));
+        }
+
+        data.get(..self.len())
+            .ok_or_else(|| breadx::Error::make_msg("buffer is too small for the image"))
+    }
+
+    /// Make sure that scanlines have no padding at their ends.
+    fn check_unpadded<P: Pixel>(&self) -> breadx::Result<()> {
+        if self.stride == self.width * P::SIZE {
+            Ok(())
+        } else {
+            Err(breadx::Error::make_msg("scanlines of the image are padded"))
+        }
+    }
+}
+
+/// An iterator over the rows of a Z image, as slices of whole pixels.
+///
+/// This is returned by `ImageLayout::rows`.
+#[derive(Debug, Clone)]
+pub struct Rows<'a, P> {
+    /// The scanlines of the image, including their padding.
+    chunks: Chunks<'a, P>,
+    /// The number of pixels in a row.
+    width: usize,
+}
+
+/// An iterator over the rows of a Z image, as mutable slices of whole
+/// pixels.
+///
+/// This is returned by `ImageLayout::rows_mut`.
+#[derive(Debug)]
+pub struct RowsMut<'a, P> {
+    /// The scanlines of the image, including their padding.
+    chunks: ChunksMut<'a, P>,
+    /// The number of pixels in a row.
+    width: usize,
+}
+
+impl<'a, P> Iterator for Rows<'a, P> {
+    type Item = &'a [P];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let width = self.width;
+        self.chunks.next().map(|row| &row[..width])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<'a, P> DoubleEndedIterator for Rows<'a, P> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let width = self.width;
+        self.chunks.next_back().map(|row| &row[..width])
+    }
+}
+
+impl<'a, P> ExactSizeIterator for Rows<'a, P> {}
+
+impl<'a, P> Iterator for RowsMut<'a, P> {
+    type Item = &'a mut [P];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let width = self.width;
+        self.chunks.next().map(|row| &mut row[..width])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<'a, P> DoubleEndedIterator for RowsMut<'a, P> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let width = self.width;
+        self.chunks.next_back().map(|row| &mut row[..width])
+    }
+}
+
+impl<'a, P> ExactSizeIterator for RowsMut<'a, P> {}
+
+/// A type that can hold a pixel of a Z image.
+///
+/// This is implemented for `u8`, `u16` and `u32`, for images with 8, 16
+/// and 32 bits per pixel.
+pub trait Pixel: Copy + 'static + private::Sealed {
+    /// The number of bytes in a pixel.
+    const SIZE: usize;
+}
+
+impl Pixel for u8 {
+    const SIZE: usize = 1;
+}
+
+impl Pixel for u16 {
+    const SIZE: usize = 2;
+}
+
+impl Pixel for u32 {
+    const SIZE: usize = 4;
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+/// Reinterpret bytes as pixels, failing if they are misaligned.
+///
+/// Segments are page aligned and the private buffers of `ShmBuffer` are
+/// aligned to four bytes, so this only fails for storage from elsewhere.
+#[allow(unsafe_code)]
+fn cast<P: Pixel>(bytes: &[u8]) -> breadx::Result<&[P]> {
+    // SAFETY: pixels are plain integers, so any bytes are valid for them
+    match unsafe { bytes.align_to::<P>() } {
+        (&[], pixels, &[]) => Ok(pixels),
+        _ => Err(breadx::Error::make_msg(
+            "image data is not aligned for the pixel type",
+        )),
+    }
+}
+
+/// Reinterpret bytes as mutable pixels, failing if they are misaligned.
+#[allow(unsafe_code)]
+fn cast_mut<P: Pixel>(bytes: &mut [u8]) -> breadx::Result<&mut [P]> {
+    // SAFETY: pixels are plain integers, so any bytes are valid for them,
+    // and any pixel is valid as bytes
+    match unsafe { bytes.align_to_mut::<P>() } {
+        (&mut [], pixels, &mut []) => Ok(pixels),
+        _ => Err(breadx::Error::make_msg(
+            "image data is not aligned for the pixel type",
+        )),
+    }
+}
+
+/// Create an image laid out the way the server expects.
+///
+/// This is `Image::with_display`, except that XY images use the bitmap
+/// bit order of the server instead of its byte order, and layouts that
+/// `breadx_image` can't represent are refused instead of panicking.
+pub fn server_image<Storage: AsRef<[u8]>>(
+    storage: Storage,
+    width: u16,
+    height: u16,
+    format: ImageFormat,
+    depth: u8,
+    setup: &Setup,
+) -> breadx::Result<Image<Storage>> {
+    match format {
+        ImageFormat::Z_PIXMAP => {
+            let pixmap = setup
+                .pixmap_formats
+                .iter()
+                .find(|pixmap| pixmap.depth == depth);
+
+            if let Some(pixmap) = pixmap {
+                BitsPerPixel::try_from(pixmap.bits_per_pixel).map_err(|_| {
+                    breadx::Error::make_msg("server uses an unsupported number of bits per pixel")
+                })?;
+            }
+        }
+        ImageFormat::XY_BITMAP | ImageFormat::XY_PIXMAP => {
+            Quantum::try_from(setup.bitmap_format_scanline_unit)
+                .map_err(|_| breadx::Error::make_msg("server uses an unsupported scanline unit"))?;
+        }
+        _ => return Err(breadx::Error::make_msg("unknown image format")),
+    }
+
+    let image = Image::with_display(storage, width, height, format, depth, setup)?;
+
+    match *image.format() {
+        Format::Xy {
+            format,
+            quantum,
+            bit_order,
+            left_pad,
+        } if bit_order != setup.bitmap_format_bit_order => {
+            let format = Format::Xy {
+                format,
+                quantum,
+                bit_order: setup.bitmap_format_bit_order,
+                left_pad,
+            };
+
+            Ok(Image::new(
+                image.into_storage(),
+                width,
+                height,
+                format,
+                setup.image_byte_order,
+                setup.bitmap_format_scanline_pad,
+            ))
+        }
+        _ => Ok(image),
+    }
+}
+
+/// Make sure that the server will read an image in the given format the
+/// same way that it was written.
+///
+/// The byte order and scanline pad of an image can't be inspected, so
+/// only images created through `server_image` or `Image::with_display`
+/// are known to match the server in those.
+pub(crate) fn check_server_format(format: &Format, setup: &Setup) -> breadx::Result<()> {
+    let matches = match *format {
+        Format::Z {
+            depth,
+            bits_per_pixel,
+        } => setup
+            .pixmap_formats
+            .iter()
+            .find(|pixmap| pixmap.depth == depth)
+            .is_some_and(|pixmap| pixmap.bits_per_pixel == bits_per_pixel as u8),
+        Format::Xy {
+            quantum, bit_order, ..
+        } => {
+            quantum as u8 == setup.bitmap_format_scanline_unit
+                && bit_order == setup.bitmap_format_bit_order
+        }
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        Err(breadx::Error::make_msg(
+            "image is not laid out the way the server expects",
+        ))
+    }
+}
+
+pub(crate) fn pad_to(val: usize, pad: usize) -> usize {
+    val + (pad - (val % pad)) % pad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(byte_order: ImageOrder, bit_order: ImageOrder, unit: u8) -> Setup {
+        Setup {
+            image_byte_order: byte_order,
+            bitmap_format_bit_order: bit_order,
+            bitmap_format_scanline_unit: unit,
+            bitmap_format_scanline_pad: 32,
+            pixmap_formats: [(1, 1), (4, 4), (24, 32)]
+                .iter()
+                .map(
+                    |&(depth, bits_per_pixel)| breadx::protocol::xproto::Format {
+                        depth,
+                        bits_per_pixel,
+                        scanline_pad: 32,
+                    },
+                )
+                .collect(),
+            ..Setup::default()
+        }
+    }
+
+    fn bounds(ranges: impl Iterator<Item = Range<usize>>) -> Vec<(usize, usize)> {
+        ranges.map(|range| (range.start, range.end)).collect()
+    }
+
+    fn z_layout(width: u16, height: u16, depth: u8) -> ImageLayout {
+        let setup = setup(ImageOrder::LSB_FIRST, ImageOrder::LSB_FIRST, 32);
+        ImageLayout::with_format(width, height, ImageFormat::Z_PIXMAP, depth, &setup).unwrap()
+    }
+
+    #[test]
+    fn bit_position_in_every_order() {
+        // pixel 9 of the second scanline, whose unit starts at byte 8
+        let cases = [
+            (ImageOrder::LSB_FIRST, ImageOrder::LSB_FIRST, 32, (9, 0x02)),
+            (ImageOrder::MSB_FIRST, ImageOrder::MSB_FIRST, 32, (9, 0x40)),
+            (ImageOrder::LSB_FIRST, ImageOrder::MSB_FIRST, 32, (10, 0x40)),
+            (ImageOrder::MSB_FIRST, ImageOrder::LSB_FIRST, 32, (10, 0x02)),
+            (ImageOrder::MSB_FIRST, ImageOrder::LSB_FIRST, 16, (8, 0x02)),
+        ];
+
+        for (byte_order, bit_order, unit, expected) in cases {
+            let setup = setup(byte_order, bit_order, unit);
+            let layout =
+                ImageLayout::with_format(40, 3, ImageFormat::XY_BITMAP, 1, &setup).unwrap();
+
+            assert_eq!(
+                layout.bit_position(9, 1, 0),
+                expected,
+                "{:?} bytes, {:?} bits, {} bit units",
+                byte_order,
+                bit_order,
+                unit
+            );
+        }
+    }
+
+    #[test]
+    fn planes_and_sizes() {
+        let setup = setup(ImageOrder::LSB_FIRST, ImageOrder::LSB_FIRST, 32);
+
+        for depth in [1, 8, 16, 24] {
+            let layout =
+                ImageLayout::with_format(40, 3, ImageFormat::XY_PIXMAP, depth, &setup).unwrap();
+
+            // 40 bits are padded to 64
+            assert_eq!(layout.stride(), 8);
+            assert_eq!(layout.planes(), usize::from(depth));
+            assert_eq!(layout.plane_len(), 24);
+            assert_eq!(layout.len(), 24 * usize::from(depth));
+            assert_eq!(layout.plane_range(0), 0..24);
+
+            let last = usize::from(depth) - 1;
+            assert_eq!(layout.plane_range(last), last * 24..depth as usize * 24);
+            assert_eq!(layout.bit_position(0, 0, last).0, last * 24);
+        }
+
+        let bitmap = ImageLayout::with_format(40, 3, ImageFormat::XY_BITMAP, 24, &setup).unwrap();
+        assert_eq!(bitmap.planes(), 1);
+        assert_eq!(bitmap.len(), 24);
+    }
+
+    #[test]
+    fn plane_pixel_round_trip() {
+        let orders = [ImageOrder::LSB_FIRST, ImageOrder::MSB_FIRST];
+
+        for (byte_order, bit_order) in orders.iter().flat_map(|&a| orders.map(|b| (a, b))) {
+            let setup = setup(byte_order, bit_order, 32);
+            let layout =
+                ImageLayout::with_format(37, 5, ImageFormat::XY_PIXMAP, 8, &setup).unwrap();
+            let mut data = vec![0; layout.len()];
+
+            for y in 0..5 {
+                for x in 0..37 {
+                    layout.set_plane_pixel(&mut data, x, y, (x * 7 + y * 31) as u32 & 0xFF);
+                }
+            }
+
+            for y in 0..5 {
+                for x in 0..37 {
+                    assert_eq!(
+                        layout.plane_pixel(&data, x, y),
+                        (x * 7 + y * 31) as u32 & 0xFF
+                    );
+                }
+            }
+
+            // plane 0 holds the most significant bit
+            layout.set_plane_pixel(&mut data, 3, 2, 0x80);
+            assert!(layout.bit(&data, 3, 2, 0));
+            assert!((1..8).all(|plane| !layout.bit(&data, 3, 2, plane)));
+        }
+    }
+
+    #[test]
+    fn row_ranges_cover_every_plane() {
+        let setup = setup(ImageOrder::LSB_FIRST, ImageOrder::LSB_FIRST, 32);
+        let layout = ImageLayout::with_format(40, 3, ImageFormat::XY_PIXMAP, 2, &setup).unwrap();
+
+        // rows past the bottom are ignored
+        let ranges = bounds(layout.row_ranges(1..10));
+        assert_eq!(ranges, [(8, 24), (32, 48)]);
+
+        assert_eq!(layout.row_ranges(3..5).count(), 0);
+        assert_eq!(layout.row_ranges(2..2).count(), 0);
+    }
+
+    #[test]
+    fn rect_ranges_are_clipped() {
+        // 32 bits per pixel, so scanlines are 40 bytes long
+        let layout = z_layout(10, 4, 24);
+
+        let ranges = bounds(layout.rect_ranges(8, 2, 10, 10));
+        assert_eq!(ranges, [(112, 120), (152, 160)]);
+
+        let ranges = bounds(layout.rect_ranges(0, 0, usize::MAX, 1));
+        assert_eq!(ranges, [(0, 40)]);
+
+        assert_eq!(layout.rect_ranges(10, 0, 5, 5).count(), 0);
+        assert_eq!(layout.rect_ranges(0, 4, 5, 5).count(), 0);
+        assert_eq!(layout.rect_ranges(2, 1, 0, 2).count(), 0);
+    }
+
+    #[test]
+    fn rect_ranges_include_partial_bytes() {
+        // one bit per pixel, in scanlines of four bytes
+        let layout = z_layout(20, 2, 1);
+        let ranges = bounds(layout.rect_ranges(3, 0, 7, 2));
+        assert_eq!(ranges, [(0, 2), (4, 6)]);
+
+        let ranges = bounds(layout.rect_ranges(9, 1, 2, 1));
+        assert_eq!(ranges, [(5, 6)]);
+
+        // the last pixel ends partway into the fourth byte
+        let ranges = bounds(layout.rect_ranges(17, 0, 10, 1));
+        assert_eq!(ranges, [(2, 3)]);
+
+        // four bits per pixel, so every byte holds two pixels
+        let layout = z_layout(5, 1, 4);
+        let ranges = bounds(layout.rect_ranges(1, 0, 2, 1));
+        assert_eq!(ranges, [(0, 2)]);
+
+        let ranges = bounds(layout.rect_ranges(4, 0, 1, 1));
+        assert_eq!(ranges, [(2, 3)]);
+    }
+}
@@ -9,22 +9,68 @@
 #![deny(unsafe_code)]
 #![allow(clippy::too_many_arguments)]
 
+mod argb;
+mod capture;
+#[cfg(feature = "composite")]
+mod composite;
+mod convert;
+#[cfg(feature = "xfixes")]
+mod cursor;
+#[cfg(feature = "damage")]
+mod damage;
+mod dither;
+mod ext;
+mod handoff;
+mod layout;
+#[cfg(feature = "randr")]
+mod monitor;
+mod region;
+#[cfg(feature = "shape")]
+mod shape;
 mod shm;
+mod staged;
+mod tracked;
+mod visual;
 use std::{
     borrow::{Borrow, BorrowMut},
     iter::Extend,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
 };
 
+pub use argb::ArgbVisual;
+pub use capture::{Frame, ScreenCapturer};
+#[cfg(feature = "composite")]
+pub use composite::WindowCapturer;
+pub use convert::{
+    export_pixels, export_pixels_to_vec, import_pixels, import_premultiplied, PixelFormat,
+};
+#[cfg(feature = "xfixes")]
+pub use cursor::CursorCompositor;
+#[cfg(feature = "damage")]
+pub use damage::{DamageCapturer, DamageFrame};
+pub use dither::{import_dithered, Dither, Palette};
+pub use ext::{ImageTarget, ShmImageExt, ShmRecvImageExt};
+pub use handoff::{handoff, PresentEnd, RenderEnd};
+use layout::check_server_format;
+pub use layout::{server_image, ImageLayout, Pixel, Rows, RowsMut};
+#[cfg(feature = "randr")]
+pub use monitor::{MonitorCapture, MonitorCapturer};
+pub use region::DamageRegion;
+#[cfg(feature = "shape")]
+pub use shape::AlphaMask;
+pub use shm::SharedView;
 use shm::{ShmBlock, ShmTransport};
+pub use staged::{ShmStagedImage, ShmStagedSegment};
+pub use tracked::TrackedShmImage;
+pub use visual::VisualInfo;
 
 use breadx::{
     display::Cookie,
     display::{Display, DisplayExt as _, DisplayFunctionsExt},
     protocol::{
         shm as xshm,
-        xproto::{Drawable, Gcontext, Pixmap},
-        Event,
+        xproto::{ColormapAlloc, Drawable, Gcontext, ImageFormat, Pixmap, Window},
+        ErrorKind, Event,
     },
     Result,
 };
@@ -49,6 +95,28 @@ pub struct ShmBuffer {
 pub type ShmImage = Image<ShmSegment>;
 pub type ShmRecvImage = Image<ShmBuffer>;
 
+// images are handed between threads, so make sure they stay able to be
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<ShmImage>();
+    assert_send_sync::<ShmRecvImage>();
+    assert_send_sync::<ShmStagedImage>();
+};
+
+/// An SHM image capture that has been sent to the server, but whose reply
+/// has not been received yet.
+///
+/// The image stays borrowed until the capture is finished, since the
+/// server may still be writing to it.
+#[must_use = "the image is not populated until the capture is finished"]
+pub struct PendingShmImage<'a> {
+    /// The image being captured into.
+    image: &'a mut ShmRecvImage,
+    /// The cookie for the reply.
+    cookie: Cookie<xshm::GetImageReply>,
+}
+
 impl AsRef<[u8]> for ShmSegment {
     fn as_ref(&self) -> &[u8] {
         self.block.as_ref()
@@ -129,7 +197,7 @@ impl ShmSegment {
     /// Creates a new SHM segment and attaches it to the X11 server.
     pub fn attach(display: &mut impl Display, len: usize) -> Result<Self> {
         // first, create the underlying SHM block
-        let block = ShmBlock::new(len).unwrap();
+        let block = ShmBlock::new(len).map_err(breadx::Error::make_msg)?;
 
         // now, attach the block to the X11 server
         let seg_id = display.generate_xid()?;
@@ -148,16 +216,39 @@ impl ShmBuffer {
     /// Creates a new SHM receiver and attaches it to the X11 server.
     pub fn attach(display: &mut impl Display, len: usize) -> Result<Self> {
         // first, create the underlying SHM block
-        let block = ShmTransport::new(len).unwrap();
+        let block = ShmTransport::new(len).map_err(breadx::Error::make_msg)?;
+
+        Self::attach_transport(display, block)
+    }
+
+    /// Creates a new SHM receiver in zero-copy mode and attaches it to the
+    /// X11 server.
+    ///
+    /// In zero-copy mode, the buffer does not keep a private copy of the
+    /// segment. The image data is read and written directly in shared
+    /// memory, and `repopulate` and `publish` do nothing. Use `shared`
+    /// instead of dereferencing the buffer if the server may still be
+    /// writing to it.
+    ///
+    /// # Safety
+    ///
+    /// The X11 server must be trusted not to write to the segment while
+    /// it is borrowed, which means it must not be used in a request that
+    /// writes to it until that borrow has ended.
+    #[allow(unsafe_code)]
+    pub unsafe fn attach_trusted(display: &mut impl Display, len: usize) -> Result<Self> {
+        // first, create the underlying SHM block
+        let block = ShmTransport::new_zero_copy(len).map_err(breadx::Error::make_msg)?;
+
+        Self::attach_transport(display, block)
+    }
 
+    fn attach_transport(display: &mut impl Display, transport: ShmTransport) -> Result<Self> {
         // now, attach the block to the X11 server
         let seg_id = display.generate_xid()?;
-        display.shm_attach_checked(seg_id, block.shm_id() as _, false)?;
+        display.shm_attach_checked(seg_id, transport.shm_id() as _, false)?;
 
-        Ok(Self {
-            transport: block,
-            seg_id,
-        })
+        Ok(Self { transport, seg_id })
     }
 
     /// Detaches the SHM segment from the server.
@@ -165,6 +256,38 @@ impl ShmBuffer {
         display.shm_detach_checked(self.seg_id)
     }
 
+    /// Detaches the SHM segment from the server and returns the private
+    /// copy of its contents.
+    ///
+    /// In zero-copy mode, the segment is copied into a new block.
+    pub fn into_owned(self, display: &mut impl Display) -> Result<Box<[u8]>> {
+        display.shm_detach_checked(self.seg_id)?;
+        Ok(self.transport.into_inner())
+    }
+
+    /// Takes the private copy of the segment's contents, leaving a zeroed
+    /// block in its place.
+    ///
+    /// The buffer stays attached and can be used for the next capture.
+    /// In zero-copy mode, the segment is copied into a new block.
+    pub fn take_owned(&mut self) -> Box<[u8]> {
+        self.transport.take_inner()
+    }
+
+    /// Tell whether this buffer was created in zero-copy mode.
+    pub fn is_zero_copy(&self) -> bool {
+        self.transport.is_zero_copy()
+    }
+
+    /// Get a view into the live contents of the shared segment.
+    ///
+    /// Unlike dereferencing the buffer, this never exposes references into
+    /// memory the server can write to. It is the safe way to peek at the
+    /// segment without a full `repopulate`, even in zero-copy mode.
+    pub fn shared(&self) -> SharedView<'_> {
+        self.transport.shared_view()
+    }
+
     #[allow(unsafe_code)]
     pub fn repopulate(&mut self) {
         unsafe {
@@ -172,8 +295,96 @@ impl ShmBuffer {
         }
     }
 
-    fn shm_id(&self) -> i32 {
-        self.transport.shm_id()
+    /// Repopulate only the first `len` bytes of the buffer.
+    #[allow(unsafe_code)]
+    pub(crate) fn repopulate_len(&mut self, len: usize) {
+        let len = len.min(self.transport.len());
+
+        unsafe {
+            self.transport.repopulate_range(0..len);
+        }
+    }
+
+    /// Repopulate only the given rows of an image stored in this buffer.
+    #[allow(unsafe_code)]
+    pub fn repopulate_rows(&mut self, layout: &ImageLayout, rows: Range<usize>) {
+        for range in layout.row_ranges(rows) {
+            unsafe {
+                self.transport.repopulate_range(range);
+            }
+        }
+    }
+
+    /// Repopulate only the given rectangle of an image stored in this buffer.
+    #[allow(unsafe_code)]
+    pub fn repopulate_rect(
+        &mut self,
+        layout: &ImageLayout,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) {
+        for range in layout.rect_ranges(x, y, width, height) {
+            unsafe {
+                self.transport.repopulate_range(range);
+            }
+        }
+    }
+
+    /// Publish the contents of this buffer into the shared segment.
+    ///
+    /// This is useful when the buffer backs a pixmap created using
+    /// `shm_create_pixmap_transport`.
+    #[allow(unsafe_code)]
+    pub fn publish(&mut self) {
+        unsafe {
+            self.transport.publish();
+        }
+    }
+
+    /// Publish only the given rows of an image stored in this buffer.
+    #[allow(unsafe_code)]
+    pub fn publish_rows(&mut self, layout: &ImageLayout, rows: Range<usize>) {
+        for range in layout.row_ranges(rows) {
+            unsafe {
+                self.transport.publish_range(range);
+            }
+        }
+    }
+
+    /// Publish only the given rectangle of an image stored in this buffer.
+    #[allow(unsafe_code)]
+    pub fn publish_rect(
+        &mut self,
+        layout: &ImageLayout,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) {
+        for range in layout.rect_ranges(x, y, width, height) {
+            unsafe {
+                self.transport.publish_range(range);
+            }
+        }
+    }
+}
+
+impl<'a> PendingShmImage<'a> {
+    /// Get the cookie for the reply.
+    pub fn cookie(&self) -> Cookie<xshm::GetImageReply> {
+        self.cookie
+    }
+
+    /// Wait for the capture to finish and populate the image.
+    pub fn finish(self, display: &mut (impl Display + ?Sized)) -> Result<xshm::GetImageReply> {
+        let reply = display.wait_for_reply(self.cookie)?;
+
+        // SAFETY: the image is now populated
+        self.image.storage_mut().repopulate();
+
+        Ok(reply)
     }
 }
 
@@ -188,22 +399,138 @@ pub trait ShmDisplayExt: Display {
         y: i16,
         plane_mask: u32,
     ) -> Result<xshm::GetImageReply> {
-        let reply = self.shm_get_image_immediate(
+        self.shm_get_ximage_pending(image, drawable, x, y, plane_mask)?
+            .finish(self)
+    }
+
+    /// Start getting an image from the server through an SHM transport,
+    /// without waiting for the reply.
+    ///
+    /// Several captures can be in flight at once this way. Call `finish`
+    /// on the returned capture to wait for it.
+    ///
+    /// For XY pixmaps, only the planes in `plane_mask` are transferred, and
+    /// they are packed together at the start of the image. XY bitmaps are
+    /// captured as single planes, so `plane_mask` must select exactly one
+    /// plane for them.
+    fn shm_get_ximage_pending<'a>(
+        &mut self,
+        image: &'a mut ShmRecvImage,
+        drawable: impl Into<Drawable>,
+        x: i16,
+        y: i16,
+        plane_mask: u32,
+    ) -> Result<PendingShmImage<'a>> {
+        check_server_format(image.format(), self.setup())?;
+
+        // the server only sends XY pixmaps, but a single plane of one is
+        // laid out exactly like a bitmap
+        let format = match image.format().format() {
+            ImageFormat::XY_BITMAP if plane_mask.count_ones() != 1 => {
+                return Err(breadx::Error::make_msg(
+                    "bitmaps can only be captured one plane at a time",
+                ))
+            }
+            ImageFormat::XY_BITMAP => ImageFormat::XY_PIXMAP,
+            format => format,
+        };
+
+        let cookie = self.shm_get_image(
             drawable.into(),
             x,
             y,
             image.width() as _,
             image.height() as _,
             plane_mask,
-            image.format().format().into(),
-            image.storage().shm_id() as _,
+            format.into(),
+            image.storage().seg_id,
             0,
         )?;
 
-        // SAFETY: the image is now populated
-        image.storage_mut().repopulate();
+        Ok(PendingShmImage { image, cookie })
+    }
 
-        Ok(reply)
+    /// Get an image from the server through an SHM transport, adopting
+    /// the depth and visual of the drawable.
+    ///
+    /// The image in `slot` is rebuilt to match the reply. Its buffer is
+    /// reused if it is large enough for any depth, and replaced otherwise.
+    /// The visual is `None` if the drawable has none, which is the case
+    /// for pixmaps. If the capture fails, the buffer is detached and the
+    /// slot is left empty.
+    fn shm_capture_ximage<'a>(
+        &mut self,
+        slot: &'a mut Option<ShmRecvImage>,
+        drawable: impl Into<Drawable>,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        plane_mask: u32,
+    ) -> Result<(&'a mut ShmRecvImage, Option<VisualInfo>)>
+    where
+        Self: Sized,
+    {
+        let format = ImageFormat::Z_PIXMAP;
+
+        // the depth isn't known until the reply arrives, so make room for
+        // the largest one
+        let len = self
+            .setup()
+            .pixmap_formats
+            .iter()
+            .map(|pixmap| {
+                ImageLayout::with_format(width, height, format, pixmap.depth, self.setup())
+                    .map(|layout| layout.len())
+            })
+            .try_fold(0, |len, other| other.map(|other| len.max(other)))?;
+
+        let storage = match slot.take() {
+            Some(image) if image.storage().len() >= len => image.into_storage(),
+            Some(image) => {
+                image.into_storage().detach(self)?;
+                ShmBuffer::attach(self, len)?
+            }
+            None => ShmBuffer::attach(self, len)?,
+        };
+
+        let reply = self.shm_get_image_immediate(
+            drawable.into(),
+            x,
+            y,
+            width,
+            height,
+            plane_mask,
+            format.into(),
+            storage.seg_id,
+            0,
+        );
+
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) => {
+                storage.detach(self)?;
+                return Err(err);
+            }
+        };
+
+        let seg_id = storage.seg_id;
+        let image = server_image(storage, width, height, format, reply.depth, self.setup());
+        let mut image = match image {
+            Ok(image) => image,
+            Err(err) => {
+                // the buffer is gone, but the server still has it attached
+                self.shm_detach_checked(seg_id)?;
+                return Err(err);
+            }
+        };
+
+        // SAFETY: the image is now populated, but only as far as the reply
+        // says; the rest of the buffer is sized for deeper images
+        image.storage_mut().repopulate_len(reply.size as usize);
+
+        let visual = VisualInfo::find(self.setup(), reply.visual);
+        Ok((slot.insert(image), visual))
     }
 
     /// Send an SHM image to the server.
@@ -222,6 +549,8 @@ pub trait ShmDisplayExt: Display {
         dest_y: i16,
         send_event: bool,
     ) -> Result<Cookie<()>> {
+        check_server_format(image.format(), self.setup())?;
+
         let cookie = self.shm_put_image(
             drawable.into(),
             gc.into(),
@@ -256,6 +585,8 @@ pub trait ShmDisplayExt: Display {
         dest_y: i16,
         send_event: bool,
     ) -> Result<()> {
+        check_server_format(image.format(), self.setup())?;
+
         let cookie = self.shm_put_image(
             drawable.into(),
             gc.into(),
@@ -300,28 +631,98 @@ pub trait ShmDisplayExt: Display {
         )?;
 
         // wait for the server to acknowledge the image
-        loop {
-            let event = self.wait_for_event()?;
-            let event = match event {
-                Event::ShmCompletion(shm_event) => {
-                    if shm_event.shmseg == image.storage().seg_id {
-                        break;
-                    }
-
-                    // TODO: send the event back into the event queue,
-                    // since we probably got an event meant for another
-                    // image
-                    Event::ShmCompletion(shm_event)
-                }
-                event => event,
-            };
+        wait_for_completion(self, image.storage().seg_id, queue)?;
 
-            queue.extend(Some(event));
+        Ok(())
+    }
+
+    /// Send a staged SHM image to the server without waiting for it to
+    /// finish.
+    ///
+    /// The segment is marked as in flight until the completion event for
+    /// it is received, either by `shm_publish` or by
+    /// `ShmStagedSegment::handle_completion`.
+    fn shm_put_staged_ximage(
+        &mut self,
+        image: &mut ShmStagedImage,
+        drawable: impl Into<Drawable>,
+        gc: impl Into<Gcontext>,
+        src_x: u16,
+        src_y: u16,
+        width: u16,
+        height: u16,
+        dest_x: i16,
+        dest_y: i16,
+    ) -> Result<Cookie<()>> {
+        check_server_format(image.format(), self.setup())?;
+
+        let cookie = self.shm_put_image(
+            drawable.into(),
+            gc.into(),
+            image.width() as _,
+            image.height() as _,
+            src_x,
+            src_y,
+            width,
+            height,
+            dest_x,
+            dest_y,
+            image.depth(),
+            image.format().format().into(),
+            true,
+            image.storage().seg_id,
+            0,
+        )?;
+
+        image.storage_mut().set_in_flight(cookie.sequence());
+        Ok(cookie)
+    }
+
+    /// Copy the private buffer of a staged SHM image into shared memory.
+    ///
+    /// If the previous put from the image has not completed yet, this
+    /// waits for it first. If that put failed, its error is returned and
+    /// the buffer is not published. Events that are not SHM related are
+    /// stored in the passed-in queue.
+    fn shm_publish(
+        &mut self,
+        image: &mut ShmStagedImage,
+        queue: &mut impl Extend<Event>,
+    ) -> Result<()> {
+        let segment = image.storage_mut();
+
+        if let Some(seq) = segment.in_flight() {
+            // a failed put never sends a completion event
+            if let Err(err) = self.check_for_error(seq) {
+                segment.clear_in_flight();
+                return Err(err);
+            }
+
+            let event = wait_for_completion(self, segment.seg_id, queue)?;
+            segment.handle_completion(&event);
         }
 
+        let published = segment.try_publish();
+        debug_assert!(published);
+
         Ok(())
     }
 
+    /// Find a 32-bit ARGB visual on the screen with the given root window,
+    /// and create a colormap for it.
+    ///
+    /// Images for windows of this visual should be written with
+    /// `import_premultiplied`.
+    fn create_argb_visual(&mut self, root: Window) -> Result<ArgbVisual> {
+        let visual = VisualInfo::find_argb32(self.setup(), root)
+            .ok_or_else(|| breadx::Error::make_msg("screen has no ARGB visual"))?;
+
+        let colormap = self.generate_xid()?;
+        self.create_colormap_checked(ColormapAlloc::NONE, colormap, root, visual.id())?;
+
+        Ok(ArgbVisual::new(visual, colormap))
+    }
+
     /// Create a `Pixmap` using an `ShmTransport` as a backing storage.
     fn shm_create_pixmap_transport(
         &mut self,
@@ -353,6 +754,43 @@ pub trait ShmDisplayExt: Display {
 
 impl<D: Display + ?Sized> ShmDisplayExt for D {}
 
+/// Tell whether an error is an X11 error of the given kind.
+///
+/// `breadx` doesn't expose the errors it receives, so the only way to tell
+/// them apart is by the way they are displayed.
+pub(crate) fn is_x11_error(err: &breadx::Error, kind: ErrorKind) -> bool {
+    err.to_string()
+        .starts_with(&format!("a {:?} error occurred", kind))
+}
+
+/// Wait for the completion event for the given segment.
+///
+/// Events that are not SHM related are stored in the passed-in queue.
+fn wait_for_completion<D: Display + ?Sized>(
+    display: &mut D,
+    seg_id: xshm::Seg,
+    queue: &mut impl Extend<Event>,
+) -> Result<xshm::CompletionEvent> {
+    loop {
+        let event = display.wait_for_event()?;
+        let event = match event {
+            Event::ShmCompletion(shm_event) => {
+                if shm_event.shmseg == seg_id {
+                    return Ok(shm_event);
+                }
+
+                // TODO: send the event back into the event queue,
+                // since we probably got an event meant for another
+                // image
+                Event::ShmCompletion(shm_event)
+            }
+            event => event,
+        };
+
+        queue.extend(Some(event));
+    }
+}
+
 pub mod prelude {
-    pub use crate::ShmDisplayExt;
+    pub use crate::{ShmDisplayExt, ShmImageExt, ShmRecvImageExt};
 }
@@ -0,0 +1,258 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{capture::attach_image, ShmDisplayExt, ShmRecvImage};
+use std::mem;
+
+use breadx::{
+    display::{Display, DisplayExt as _, DisplayFunctionsExt},
+    protocol::{
+        randr::{self, Notify, NotifyMask},
+        xproto::{Atom, Rectangle, Window},
+        Event,
+    },
+    Result,
+};
+
+/// Captures every monitor of a screen into its own buffer.
+///
+/// Monitors are found through RandR. RandR 1.5 monitors are used if the
+/// server supports them; otherwise, every active CRTC is treated as a
+/// monitor. When the screen configuration changes, the buffers are
+/// rebuilt on the next capture.
+pub struct MonitorCapturer {
+    /// The root window of the screen being captured.
+    root: Window,
+    /// The depth of the root window.
+    depth: u8,
+    /// Whether the server supports RandR 1.5 monitors.
+    has_monitors: bool,
+    /// The monitors being captured.
+    monitors: Vec<MonitorCapture>,
+    /// Whether the monitors need to be rebuilt before the next capture.
+    stale: bool,
+}
+
+/// The capture of a single monitor.
+pub struct MonitorCapture {
+    /// The name of the monitor, or zero if it isn't known.
+    name: Atom,
+    /// Whether this is the primary monitor.
+    primary: bool,
+    /// The area of the root window covered by the monitor.
+    geometry: Rectangle,
+    /// The image the monitor is captured into.
+    image: ShmRecvImage,
+}
+
+impl MonitorCapturer {
+    /// Start capturing the monitors of the screen with the given root
+    /// window.
+    ///
+    /// At least RandR 1.3 must be available on the server. This selects
+    /// screen change notifications on the root window; pass them into
+    /// `handle_event` so that the buffers are rebuilt.
+    pub fn new(display: &mut impl Display, root: Window) -> Result<Self> {
+        let version = display.randr_query_version(1, 5)?;
+        let version = display.wait_for_reply(version)?;
+        if (version.major_version, version.minor_version) < (1, 3) {
+            return Err(breadx::Error::make_msg("RandR 1.3 or newer is required"));
+        }
+
+        let depth = display
+            .screens()
+            .iter()
+            .find(|screen| screen.root == root)
+            .map(|screen| screen.root_depth)
+            .ok_or_else(|| breadx::Error::make_msg("window is not a root window"))?;
+
+        display.randr_select_input_checked(
+            root,
+            NotifyMask::SCREEN_CHANGE | NotifyMask::CRTC_CHANGE,
+        )?;
+
+        Ok(Self {
+            root,
+            depth,
+            has_monitors: (version.major_version, version.minor_version) >= (1, 5),
+            monitors: Vec::new(),
+            stale: true,
+        })
+    }
+
+    /// Handle an event, returning `true` if it means that the monitors
+    /// need to be rebuilt.
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        let changed = match event {
+            Event::RandrScreenChangeNotify(notify) => notify.root == self.root,
+            // outputs and providers only affect what is captured through
+            // the CRTCs they drive, which report their own changes
+            Event::RandrNotify(notify) => {
+                notify.sub_code == Notify::CRTC_CHANGE && notify.u.as_cc().window == self.root
+            }
+            _ => false,
+        };
+
+        self.stale |= changed;
+        changed
+    }
+
+    /// Get the monitors captured so far.
+    pub fn monitors(&self) -> &[MonitorCapture] {
+        &self.monitors
+    }
+
+    /// Capture every monitor.
+    ///
+    /// If the screen configuration changed since the last capture, the
+    /// buffers are rebuilt first.
+    pub fn capture_all(&mut self, display: &mut impl Display) -> Result<&mut [MonitorCapture]> {
+        if self.stale {
+            self.rebuild(display)?;
+        }
+
+        // send all of the captures before waiting on any of them
+        let root = self.root;
+        let pending = self
+            .monitors
+            .iter_mut()
+            .map(|monitor| {
+                let Rectangle { x, y, .. } = monitor.geometry;
+                display.shm_get_ximage_pending(&mut monitor.image, root, x, y, !0)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for capture in pending {
+            capture.finish(display)?;
+        }
+
+        Ok(&mut self.monitors)
+    }
+
+    /// Detach the buffers used by this capturer from the server.
+    pub fn detach(self, display: &mut impl Display) -> Result<()> {
+        for monitor in self.monitors {
+            monitor.image.into_storage().detach(display)?;
+        }
+
+        Ok(())
+    }
+
+    /// Query the monitors of the screen and create a buffer for each.
+    ///
+    /// The old buffers are only detached once all of the new ones have
+    /// been attached, so the old monitors are kept if that fails.
+    fn rebuild(&mut self, display: &mut impl Display) -> Result<()> {
+        let layouts = if self.has_monitors {
+            self.query_monitors(display)?
+        } else {
+            self.query_crtcs(display)?
+        };
+
+        let mut monitors = Vec::with_capacity(layouts.len());
+        for (name, primary, geometry) in layouts {
+            match attach_image(display, geometry.width, geometry.height, self.depth) {
+                Ok(image) => monitors.push(MonitorCapture {
+                    name,
+                    primary,
+                    geometry,
+                    image,
+                }),
+                Err(err) => {
+                    for monitor in monitors {
+                        monitor.image.into_storage().detach(display)?;
+                    }
+
+                    return Err(err);
+                }
+            }
+        }
+
+        self.stale = false;
+        for monitor in mem::replace(&mut self.monitors, monitors) {
+            monitor.image.into_storage().detach(display)?;
+        }
+
+        Ok(())
+    }
+
+    fn query_monitors(&self, display: &mut impl Display) -> Result<Vec<(Atom, bool, Rectangle)>> {
+        let reply = display.randr_get_monitors_immediate(self.root, true)?;
+
+        Ok(reply
+            .monitors
+            .into_iter()
+            .map(|monitor| {
+                let geometry = Rectangle {
+                    x: monitor.x,
+                    y: monitor.y,
+                    width: monitor.width,
+                    height: monitor.height,
+                };
+
+                (monitor.name, monitor.primary, geometry)
+            })
+            .collect())
+    }
+
+    fn query_crtcs(&self, display: &mut impl Display) -> Result<Vec<(Atom, bool, Rectangle)>> {
+        let resources = display.randr_get_screen_resources_current_immediate(self.root)?;
+
+        // send all of the requests before waiting on any of them
+        let cookies = resources
+            .crtcs
+            .iter()
+            .map(|&crtc| display.randr_get_crtc_info(crtc, resources.config_timestamp))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut layouts = Vec::with_capacity(cookies.len());
+        for cookie in cookies {
+            let info: randr::GetCrtcInfoReply = display.wait_for_reply(cookie)?;
+
+            // disabled CRTCs have no mode
+            if info.mode != 0 && info.width != 0 && info.height != 0 {
+                let geometry = Rectangle {
+                    x: info.x,
+                    y: info.y,
+                    width: info.width,
+                    height: info.height,
+                };
+
+                layouts.push((0, false, geometry));
+            }
+        }
+
+        Ok(layouts)
+    }
+}
+
+impl MonitorCapture {
+    /// Get the name of the monitor.
+    ///
+    /// This is zero if the server does not support RandR 1.5 monitors.
+    pub fn name(&self) -> Atom {
+        self.name
+    }
+
+    /// Tell whether this is the primary monitor.
+    pub fn is_primary(&self) -> bool {
+        self.primary
+    }
+
+    /// Get the area of the root window covered by the monitor.
+    pub fn geometry(&self) -> Rectangle {
+        self.geometry
+    }
+
+    /// Get the image the monitor is captured into.
+    pub fn image(&self) -> &ShmRecvImage {
+        &self.image
+    }
+
+    /// Get the image the monitor is captured into, mutably.
+    pub fn image_mut(&mut self) -> &mut ShmRecvImage {
+        &mut self.image
+    }
+}
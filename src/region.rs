@@ -0,0 +1,255 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use breadx::protocol::xproto::Rectangle;
+use std::mem;
+
+/// The default number of rectangles a `DamageRegion` holds.
+const DEFAULT_MAX_RECTS: usize = 16;
+
+/// A set of damaged rectangles, coalesced into a bounded number of
+/// rectangles.
+///
+/// Rectangles that overlap cheaply are merged as they are added. Once the
+/// limit is exceeded, the two rectangles whose union wastes the least area
+/// are merged until the set fits again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamageRegion {
+    /// The rectangles making up the region.
+    rects: Vec<Rectangle>,
+    /// The maximum number of rectangles to keep.
+    max_rects: usize,
+}
+
+impl Default for DamageRegion {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_RECTS)
+    }
+}
+
+impl DamageRegion {
+    /// Create a new, empty region that holds at most `max_rects` rectangles.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_rects` is zero.
+    pub fn new(max_rects: usize) -> Self {
+        assert!(
+            max_rects > 0,
+            "a damage region needs at least one rectangle"
+        );
+
+        Self {
+            rects: Vec::new(),
+            max_rects,
+        }
+    }
+
+    /// The rectangles making up the region.
+    pub fn rects(&self) -> &[Rectangle] {
+        &self.rects
+    }
+
+    /// Tell whether no area is damaged.
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// The smallest rectangle containing the entire region.
+    pub fn bounds(&self) -> Option<Rectangle> {
+        self.rects.iter().copied().reduce(union)
+    }
+
+    /// Remove all rectangles from the region.
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    /// Take the rectangles out of the region, leaving it empty.
+    pub fn take(&mut self) -> Vec<Rectangle> {
+        mem::take(&mut self.rects)
+    }
+
+    /// Add a rectangle to the region.
+    pub fn add(&mut self, mut rect: Rectangle) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+
+        // merge with every rectangle that we can merge with for free
+        loop {
+            if self.rects.iter().any(|other| contains(other, &rect)) {
+                return;
+            }
+
+            let cheap = self.rects.iter().position(|other| {
+                let merged = union(*other, rect);
+                area(&merged) <= area(other) + area(&rect) - overlap(other, &rect)
+            });
+
+            match cheap {
+                Some(index) => {
+                    let other = self.rects.swap_remove(index);
+                    rect = union(other, rect);
+                }
+                None => break,
+            }
+        }
+
+        self.rects.push(rect);
+
+        // merge the cheapest pairs until we fit again
+        while self.rects.len() > self.max_rects {
+            let mut best = (0, 1, u64::MAX);
+
+            for i in 0..self.rects.len() {
+                for j in i + 1..self.rects.len() {
+                    let (a, b) = (&self.rects[i], &self.rects[j]);
+                    let waste = area(&union(*a, *b)) - (area(a) + area(b) - overlap(a, b));
+                    if waste < best.2 {
+                        best = (i, j, waste);
+                    }
+                }
+            }
+
+            let (i, j, _) = best;
+            let b = self.rects.swap_remove(j);
+            self.rects[i] = union(self.rects[i], b);
+        }
+    }
+}
+
+impl Extend<Rectangle> for DamageRegion {
+    fn extend<I: IntoIterator<Item = Rectangle>>(&mut self, iter: I) {
+        for rect in iter {
+            self.add(rect);
+        }
+    }
+}
+
+fn right(rect: &Rectangle) -> i32 {
+    i32::from(rect.x) + i32::from(rect.width)
+}
+
+fn bottom(rect: &Rectangle) -> i32 {
+    i32::from(rect.y) + i32::from(rect.height)
+}
+
+fn area(rect: &Rectangle) -> u64 {
+    u64::from(rect.width) * u64::from(rect.height)
+}
+
+fn contains(outer: &Rectangle, inner: &Rectangle) -> bool {
+    outer.x <= inner.x
+        && outer.y <= inner.y
+        && right(outer) >= right(inner)
+        && bottom(outer) >= bottom(inner)
+}
+
+fn overlap(a: &Rectangle, b: &Rectangle) -> u64 {
+    let width = right(a).min(right(b)) - i32::from(a.x.max(b.x));
+    let height = bottom(a).min(bottom(b)) - i32::from(a.y.max(b.y));
+
+    if width > 0 && height > 0 {
+        width as u64 * height as u64
+    } else {
+        0
+    }
+}
+
+fn union(a: Rectangle, b: Rectangle) -> Rectangle {
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+
+    Rectangle {
+        x,
+        y,
+        width: (right(&a).max(right(&b)) - i32::from(x)) as u16,
+        height: (bottom(&a).max(bottom(&b)) - i32::from(y)) as u16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i16, y: i16, width: u16, height: u16) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn region_of(rects: &[Rectangle]) -> DamageRegion {
+        let mut region = DamageRegion::default();
+        region.extend(rects.iter().copied());
+        region
+    }
+
+    #[test]
+    fn disjoint_rects_are_kept_apart() {
+        let region = region_of(&[rect(0, 0, 10, 10), rect(20, 20, 10, 10)]);
+
+        assert_eq!(region.rects(), [rect(0, 0, 10, 10), rect(20, 20, 10, 10)]);
+        assert_eq!(region.bounds(), Some(rect(0, 0, 30, 30)));
+    }
+
+    #[test]
+    fn overlapping_rects_are_merged() {
+        let region = region_of(&[rect(0, 0, 10, 10), rect(5, 0, 10, 10)]);
+        assert_eq!(region.rects(), [rect(0, 0, 15, 10)]);
+
+        // merging these would cover area that isn't damaged
+        let region = region_of(&[rect(0, 0, 10, 10), rect(5, 5, 10, 10)]);
+        assert_eq!(region.rects().len(), 2);
+    }
+
+    #[test]
+    fn contained_rects_are_absorbed() {
+        let region = region_of(&[rect(0, 0, 10, 10), rect(2, 2, 3, 3)]);
+        assert_eq!(region.rects(), [rect(0, 0, 10, 10)]);
+
+        let region = region_of(&[rect(2, 2, 3, 3), rect(0, 0, 10, 10)]);
+        assert_eq!(region.rects(), [rect(0, 0, 10, 10)]);
+    }
+
+    #[test]
+    fn adjacent_rects_are_merged() {
+        let region = region_of(&[rect(0, 0, 10, 10), rect(10, 0, 10, 10)]);
+        assert_eq!(region.rects(), [rect(0, 0, 20, 10)]);
+
+        let region = region_of(&[rect(0, 0, 10, 10), rect(0, 10, 10, 5)]);
+        assert_eq!(region.rects(), [rect(0, 0, 10, 15)]);
+    }
+
+    #[test]
+    fn empty_rects_are_ignored() {
+        let region = region_of(&[rect(0, 0, 0, 10), rect(0, 0, 10, 0)]);
+
+        assert!(region.is_empty());
+        assert_eq!(region.bounds(), None);
+    }
+
+    #[test]
+    fn rect_count_is_capped() {
+        let mut region = DamageRegion::new(2);
+        region.extend([
+            rect(0, 0, 10, 10),
+            rect(12, 0, 10, 10),
+            rect(100, 100, 10, 10),
+        ]);
+
+        // the closest pair wastes the least area
+        let mut rects = region.rects().to_vec();
+        rects.sort_by_key(|rect| (rect.x, rect.y));
+        assert_eq!(rects, [rect(0, 0, 22, 10), rect(100, 100, 10, 10)]);
+
+        let mut region = DamageRegion::new(1);
+        region.extend((0..8).map(|i| rect(i * 20, i * 20, 5, 5)));
+        assert_eq!(region.rects(), [rect(0, 0, 145, 145)]);
+    }
+}
@@ -0,0 +1,167 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{
+    convert::{get_pixel, is_whole_bytes, read_pixel, Channel},
+    server_image, ImageLayout, ShmImage, ShmSegment, VisualInfo,
+};
+
+use breadx::{
+    display::{Display, DisplayExt as _, DisplayFunctionsExt},
+    protocol::{
+        shape::{SK, SO},
+        xproto::{ImageFormat, Pixmap, Setup, Window},
+    },
+    Result,
+};
+use breadx_image::Image;
+
+/// A window shape taken from the alpha channel of an image.
+///
+/// The mask is kept in a depth 1 pixmap shared with the server, so it can
+/// be rebuilt from a new image without being sent over the connection.
+/// The Shape extension must be available, and the server must support
+/// shared pixmaps.
+pub struct AlphaMask {
+    /// The mask, backed by the segment the pixmap uses.
+    image: ShmImage,
+    /// The pixmap sharing the segment.
+    pixmap: Pixmap,
+}
+
+impl AlphaMask {
+    /// Create an empty mask of the given size for windows on the same
+    /// screen as `window`.
+    pub fn new(
+        display: &mut impl Display,
+        window: Window,
+        width: u16,
+        height: u16,
+    ) -> Result<Self> {
+        let version = display.shape_query_version()?;
+        display.wait_for_reply(version)?;
+
+        let version = display.shm_query_version_immediate()?;
+        if !version.shared_pixmaps || version.pixmap_format != u8::from(ImageFormat::Z_PIXMAP) {
+            return Err(breadx::Error::make_msg(
+                "server does not support shared Z pixmaps",
+            ));
+        }
+
+        let format = ImageFormat::Z_PIXMAP;
+        let layout = ImageLayout::with_format(width, height, format, 1, display.setup())?;
+        let segment = ShmSegment::attach(display, layout.len())?;
+        let seg_id = segment.seg_id;
+        let image = match server_image(segment, width, height, format, 1, display.setup()) {
+            Ok(image) => image,
+            Err(err) => {
+                // the segment is gone, but the server still has it attached
+                display.shm_detach_checked(seg_id)?;
+                return Err(err);
+            }
+        };
+
+        let pixmap = display.generate_xid()?;
+        let created =
+            display.shm_create_pixmap_checked(pixmap, window, width, height, 1, seg_id, 0);
+
+        match created {
+            Ok(()) => Ok(Self { image, pixmap }),
+            Err(err) => {
+                image.into_storage().detach(display)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Get the pixmap holding the mask.
+    pub fn pixmap(&self) -> Pixmap {
+        self.pixmap
+    }
+
+    /// Get the image holding the mask.
+    pub fn image(&self) -> &ShmImage {
+        &self.image
+    }
+
+    /// Rebuild the mask from the alpha channel of an image.
+    ///
+    /// Pixels whose alpha is at least `threshold` are inside the shape.
+    /// `visual` describes the pixels of the image, and must have an alpha
+    /// channel. Parts of the mask not covered by the image are cleared.
+    pub fn update<Storage: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        image: &Image<Storage>,
+        setup: &Setup,
+        visual: &VisualInfo,
+        threshold: u8,
+    ) -> Result<()> {
+        if visual.alpha_mask() == 0 {
+            return Err(breadx::Error::make_msg("visual has no alpha channel"));
+        }
+
+        let alpha = Channel::new(visual.alpha_mask());
+        let src_layout = ImageLayout::new(image, setup);
+        let layout = ImageLayout::new(&self.image, setup);
+        let whole_bytes = is_whole_bytes(image, &src_layout);
+        let bytes_per_pixel = src_layout.bits_per_pixel() / 8;
+
+        let data = image.storage().as_ref();
+        let mask = self.image.storage_mut().as_mut();
+        mask.fill(0);
+
+        let width = src_layout.width().min(layout.width());
+        let height = src_layout.height().min(layout.height());
+
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = if whole_bytes {
+                    let start = y * src_layout.stride() + x * bytes_per_pixel;
+                    read_pixel(
+                        &data[start..start + bytes_per_pixel],
+                        src_layout.byte_order(),
+                    )
+                } else {
+                    get_pixel(image, &src_layout, x, y)
+                };
+
+                if alpha.decode(pixel) >= threshold {
+                    layout.set_bit(mask, x, y, 0, true);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set the bounding shape of a window to the mask.
+    ///
+    /// `x` and `y` are the position of the mask relative to the window.
+    pub fn apply(&self, display: &mut impl Display, window: Window, x: i16, y: i16) -> Result<()> {
+        self.apply_kind(display, window, SK::BOUNDING, x, y)
+    }
+
+    /// Set the given shape of a window to the mask.
+    ///
+    /// Use `SK::INPUT` to only let the opaque parts of a window take input.
+    pub fn apply_kind(
+        &self,
+        display: &mut impl Display,
+        window: Window,
+        kind: SK,
+        x: i16,
+        y: i16,
+    ) -> Result<()> {
+        display.shape_mask_checked(SO::SET, kind, window, x, y, self.pixmap)
+    }
+
+    /// Free the pixmap and detach the segment.
+    ///
+    /// Shapes that were set from the mask stay in place.
+    pub fn free(self, display: &mut impl Display) -> Result<()> {
+        display.free_pixmap_checked(self.pixmap)?;
+        self.image.into_storage().detach(display)
+    }
+}
@@ -10,8 +10,11 @@ use libc::{c_int, c_uint};
 use std::{
     borrow::{Borrow, BorrowMut},
     io::{Error, Result},
-    ops::{Deref, DerefMut},
-    ptr::{null_mut, slice_from_raw_parts_mut, NonNull},
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut, Range},
+    ptr::{self, null_mut, slice_from_raw_parts_mut, NonNull},
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
 };
 
 macro_rules! syscall {
@@ -55,6 +58,21 @@ pub(crate) struct ShmBlock {
     ptr: NonNull<[u8]>,
 }
 
+/// A read-only view into the live contents of a server-writable segment.
+///
+/// The server may write to the segment at any time, so this type never
+/// hands out references into it. Every access copies the data out, either
+/// through volatile reads or, for aligned integers, through atomic loads
+/// that cannot be torn.
+pub struct SharedView<'a> {
+    /// The start of the segment.
+    ptr: NonNull<u8>,
+    /// The length of the segment.
+    len: usize,
+    /// The segment this view borrows from.
+    _marker: PhantomData<&'a ShmBlock>,
+}
+
 /// A block of memory that uses SHM as a transport.
 ///
 /// The inner `ShmBlock` in this case is not required to only be read
@@ -66,13 +84,50 @@ pub(crate) struct ShmBlock {
 ///
 /// While a race condition is still possible in this way, its impacts
 /// are significantly less catastrophic than it would be
+///
+/// If the server is trusted, the transport can also be created in
+/// zero-copy mode. In this mode there is no heap block; the user
+/// interacts with the SHM block directly.
 pub(crate) struct ShmTransport {
     /// The user-accessible block of memory.
-    block: Box<[u8]>,
+    ///
+    /// This is `None` if the transport is in zero-copy mode.
+    block: Option<AlignedBlock>,
     /// The SHM segment associated with this block.
     segment: ShmBlock,
 }
 
+/// A heap block of memory whose contents start on a four byte boundary.
+///
+/// A `[u8]` is only guaranteed to be byte aligned, so the block is
+/// allocated with room to spare and its contents start at the first
+/// aligned byte. This lets the contents be viewed as pixels of any width,
+/// just like the page aligned segments.
+struct AlignedBlock {
+    /// The allocation holding the contents.
+    block: Vec<u8>,
+    /// The offset of the contents into the allocation.
+    offset: usize,
+    /// The length of the contents.
+    len: usize,
+}
+
+// SAFETY: the mapping belongs to the process rather than to the thread
+//         that created it, and is unmapped in the same way from any thread.
+//         ShmBlock owns it exclusively, just like a Box<[u8]>.
+unsafe impl Send for ShmBlock {}
+
+// SAFETY: a shared ShmBlock only hands out shared slices of the mapping,
+//         and server-writable blocks are only read through raw pointers
+//         by `ShmTransport` and `SharedView`, which never race with writes
+//         from this process.
+unsafe impl Sync for ShmBlock {}
+
+// SAFETY: a SharedView is equivalent to a &ShmBlock, and only reads the
+//         segment through volatile and atomic loads.
+unsafe impl Send for SharedView<'_> {}
+unsafe impl Sync for SharedView<'_> {}
+
 impl AsRef<[u8]> for ShmBlock {
     fn as_ref(&self) -> &[u8] {
         // SAFETY: ptr is always a valid pointer to a slice of memory
@@ -124,27 +179,47 @@ impl Drop for ShmBlock {
     }
 }
 
+impl Deref for AlignedBlock {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.block[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBlock {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.block[self.offset..self.offset + self.len]
+    }
+}
+
 impl AsRef<[u8]> for ShmTransport {
     fn as_ref(&self) -> &[u8] {
-        &self.block
+        match self.block {
+            Some(ref block) => block,
+            None => &self.segment,
+        }
     }
 }
 
 impl AsMut<[u8]> for ShmTransport {
     fn as_mut(&mut self) -> &mut [u8] {
-        &mut self.block
+        match self.block {
+            Some(ref mut block) => block,
+            None => &mut self.segment,
+        }
     }
 }
 
 impl Borrow<[u8]> for ShmTransport {
     fn borrow(&self) -> &[u8] {
-        &self.block
+        self.as_ref()
     }
 }
 
 impl BorrowMut<[u8]> for ShmTransport {
     fn borrow_mut(&mut self) -> &mut [u8] {
-        &mut self.block
+        self.as_mut()
     }
 }
 
@@ -152,13 +227,13 @@ impl Deref for ShmTransport {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.block
+        self.as_ref()
     }
 }
 
 impl DerefMut for ShmTransport {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.block
+        self.as_mut()
     }
 }
 
@@ -219,19 +294,150 @@ impl ShmBlock {
 
     /// Get the length of the memory associated with this segment.
     pub fn len(&self) -> usize {
-        self.as_ref().len()
+        self.ptr.len()
     }
 
     /// Tell whether this item is empty.
+    #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
-        self.as_ref().is_empty()
+        self.len() == 0
+    }
+}
+
+impl AlignedBlock {
+    /// The alignment of the contents, enough for 32-bit pixels.
+    const ALIGN: usize = mem::align_of::<u32>();
+
+    /// Allocate a zeroed block of the given length.
+    fn zeroed(len: usize) -> Self {
+        let block = vec![0; len + Self::ALIGN - 1];
+        let misalignment = block.as_ptr() as usize % Self::ALIGN;
+
+        Self {
+            block,
+            offset: (Self::ALIGN - misalignment) % Self::ALIGN,
+            len,
+        }
+    }
+
+    /// Turn the block into a boxed slice of its contents.
+    ///
+    /// The contents are only moved if they don't start the allocation,
+    /// and the spare room is given back to the allocator.
+    fn into_boxed_slice(self) -> Box<[u8]> {
+        let Self {
+            mut block,
+            offset,
+            len,
+        } = self;
+
+        if offset != 0 {
+            block.copy_within(offset..offset + len, 0);
+        }
+        block.truncate(len);
+        block.into_boxed_slice()
+    }
+}
+
+impl<'a> SharedView<'a> {
+    /// Get the length of the segment.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Tell whether the segment is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Read the byte at the given index.
+    pub fn get(&self, index: usize) -> Option<u8> {
+        if index < self.len {
+            // SAFETY: index is in bounds
+            Some(unsafe { ptr::read_volatile(self.ptr.as_ptr().add(index)) })
+        } else {
+            None
+        }
+    }
+
+    /// Read a native-endian `u16` at the given offset.
+    ///
+    /// Returns `None` if the offset is out of bounds or is not aligned
+    /// to two bytes.
+    pub fn read_u16(&self, offset: usize) -> Option<u16> {
+        let ptr = self.aligned_ptr(offset, mem::size_of::<u16>())?;
+
+        // SAFETY: ptr is in bounds and aligned, and atomics are the only way
+        //         to access memory shared with another process soundly
+        let atomic = unsafe { &*(ptr as *const AtomicU16) };
+        Some(atomic.load(Ordering::Relaxed))
+    }
+
+    /// Read a native-endian `u32` at the given offset.
+    ///
+    /// Returns `None` if the offset is out of bounds or is not aligned
+    /// to four bytes.
+    pub fn read_u32(&self, offset: usize) -> Option<u32> {
+        let ptr = self.aligned_ptr(offset, mem::size_of::<u32>())?;
+
+        // SAFETY: same as above
+        let atomic = unsafe { &*(ptr as *const AtomicU32) };
+        Some(atomic.load(Ordering::Relaxed))
+    }
+
+    /// Copy the bytes starting at `offset` into `dst`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the copied range is out of bounds.
+    pub fn copy_to_slice(&self, offset: usize, dst: &mut [u8]) {
+        let in_bounds = offset
+            .checked_add(dst.len())
+            .is_some_and(|end| end <= self.len);
+        assert!(in_bounds, "range out of bounds for shared segment");
+
+        // SAFETY: the range is in bounds
+        unsafe { copy_from_shared(dst, self.ptr.as_ptr().add(offset)) }
+    }
+
+    /// Copy the given range out of the segment and pass it to `f`.
+    ///
+    /// `f` sees a private snapshot of the data, so anything it checks
+    /// about the data still holds once it returns. This makes it the right
+    /// place to validate values that the server controls.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn validate<T>(
+        &self,
+        range: Range<usize>,
+        f: impl FnOnce(&[u8]) -> Option<T>,
+    ) -> Option<T> {
+        let mut snapshot = vec![0; range.end.saturating_sub(range.start)];
+        self.copy_to_slice(range.start, &mut snapshot);
+        f(&snapshot)
+    }
+
+    fn aligned_ptr(&self, offset: usize, size: usize) -> Option<*const u8> {
+        if offset.checked_add(size)? > self.len {
+            return None;
+        }
+
+        // SAFETY: offset is in bounds
+        let ptr = unsafe { self.ptr.as_ptr().add(offset) };
+        if ptr.align_offset(size) == 0 {
+            Some(ptr)
+        } else {
+            None
+        }
     }
 }
 
 impl ShmTransport {
     /// Create a new available SHM transport of the specified size.
     pub fn new(len: usize) -> Result<ShmTransport> {
-        let block = vec![0; len].into_boxed_slice();
+        let block = AlignedBlock::zeroed(len);
 
         // SAFETY: SHM transport is not exposed to the user, so we can make it
         //         server-writable
@@ -239,14 +445,66 @@ impl ShmTransport {
             unsafe { ShmBlock::with_flags(len, libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO) }?;
 
         Ok(Self {
-            block,
+            block: Some(block),
             segment: transport,
         })
     }
 
+    /// Create a new SHM transport of the specified size whose segment can
+    /// only be read by the server.
+    pub fn new_read_only(len: usize) -> Result<ShmTransport> {
+        let block = AlignedBlock::zeroed(len);
+        let segment = ShmBlock::new(len)?;
+
+        Ok(Self {
+            block: Some(block),
+            segment,
+        })
+    }
+
+    /// Create a new SHM transport of the specified size that does not
+    /// keep a copy of the segment on the heap.
+    ///
+    /// # Safety
+    ///
+    /// The server that the segment is shared with must not modify it
+    /// while the user holds a reference to it.
+    pub unsafe fn new_zero_copy(len: usize) -> Result<ShmTransport> {
+        let segment = ShmBlock::with_flags(len, libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO)?;
+
+        Ok(Self {
+            block: None,
+            segment,
+        })
+    }
+
+    /// Tell whether this transport is in zero-copy mode.
+    pub fn is_zero_copy(&self) -> bool {
+        self.block.is_none()
+    }
+
     /// Get the block of memory backing this transport.
+    ///
+    /// In zero-copy mode, this copies the segment into a new block.
     pub fn into_inner(self) -> Box<[u8]> {
-        self.block
+        match self.block {
+            Some(block) => block.into_boxed_slice(),
+            None => self.segment.as_ref().into(),
+        }
+    }
+
+    /// Take the block of memory backing this transport, leaving a zeroed
+    /// block in its place.
+    ///
+    /// In zero-copy mode, this copies the segment into a new block.
+    pub fn take_inner(&mut self) -> Box<[u8]> {
+        match self.block {
+            Some(ref mut block) => {
+                let fresh = AlignedBlock::zeroed(block.len());
+                mem::replace(block, fresh).into_boxed_slice()
+            }
+            None => self.segment.as_ref().into(),
+        }
     }
 
     /// Get the ID of the segment associated with this transport.
@@ -254,10 +512,12 @@ impl ShmTransport {
         self.segment.shm_id()
     }
 
+    #[allow(dead_code)]
     pub(crate) unsafe fn segment(&self) -> &ShmBlock {
         &self.segment
     }
 
+    #[allow(dead_code)]
     pub(crate) unsafe fn segment_mut(&mut self) -> &mut ShmBlock {
         &mut self.segment
     }
@@ -268,7 +528,7 @@ impl ShmTransport {
     ///
     /// SHM block must not be in use by the server.
     pub unsafe fn repopulate(&mut self) {
-        self.block.copy_from_slice(&self.segment);
+        self.repopulate_range(0..self.segment.len());
     }
 
     /// Publish the data into the segment.
@@ -277,6 +537,103 @@ impl ShmTransport {
     ///
     /// SHM block must not be in use by the server.
     pub unsafe fn publish(&mut self) {
-        self.segment.copy_from_slice(&self.block);
+        self.publish_range(0..self.segment.len());
+    }
+
+    /// Repopulate a range of bytes in the block.
+    ///
+    /// # Safety
+    ///
+    /// The range must not be in use by the server.
+    pub unsafe fn repopulate_range(&mut self, range: Range<usize>) {
+        if let Some(ref mut block) = self.block {
+            // the server may write to the segment concurrently, so never
+            // create a reference to it
+            let dst = &mut block[range.clone()];
+            copy_from_shared(dst, self.segment.as_ptr().add(range.start));
+        }
+    }
+
+    /// Publish a range of bytes into the segment.
+    ///
+    /// # Safety
+    ///
+    /// The range must not be in use by the server.
+    pub unsafe fn publish_range(&mut self, range: Range<usize>) {
+        if let Some(ref block) = self.block {
+            let src = &block[range.clone()];
+            copy_to_shared(self.segment.as_mut_ptr().add(range.start), src);
+        }
+    }
+
+    /// Get a view into the live contents of the segment.
+    pub fn shared_view(&self) -> SharedView<'_> {
+        SharedView {
+            // SAFETY: ShmBlock always holds a valid pointer
+            ptr: unsafe { NonNull::new_unchecked(self.segment.as_ptr() as *mut u8) },
+            len: self.segment.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// Copy bytes out of memory that another process may write to.
+///
+/// Every byte is read exactly once using volatile reads, word by word
+/// where possible.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `dst.len()` bytes.
+unsafe fn copy_from_shared(dst: &mut [u8], src: *const u8) {
+    let len = dst.len();
+    let mut i = 0;
+
+    // read bytes until the source is aligned
+    while i < len && src.add(i).align_offset(WORD) != 0 {
+        dst[i] = ptr::read_volatile(src.add(i));
+        i += 1;
+    }
+
+    // read whole words
+    while i + WORD <= len {
+        let word = ptr::read_volatile(src.add(i) as *const usize);
+        dst[i..i + WORD].copy_from_slice(&word.to_ne_bytes());
+        i += WORD;
+    }
+
+    // read the remaining bytes
+    while i < len {
+        dst[i] = ptr::read_volatile(src.add(i));
+        i += 1;
+    }
+}
+
+/// Copy bytes into memory that another process may read from.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `src.len()` bytes.
+unsafe fn copy_to_shared(dst: *mut u8, src: &[u8]) {
+    let len = src.len();
+    let mut i = 0;
+
+    while i < len && dst.add(i).align_offset(WORD) != 0 {
+        ptr::write_volatile(dst.add(i), src[i]);
+        i += 1;
+    }
+
+    while i + WORD <= len {
+        let mut word = [0; WORD];
+        word.copy_from_slice(&src[i..i + WORD]);
+        ptr::write_volatile(dst.add(i) as *mut usize, usize::from_ne_bytes(word));
+        i += WORD;
+    }
+
+    while i < len {
+        ptr::write_volatile(dst.add(i), src[i]);
+        i += 1;
     }
 }
@@ -0,0 +1,179 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{shm::ShmTransport, ImageLayout};
+use std::{
+    borrow::{Borrow, BorrowMut},
+    ops::{Deref, DerefMut},
+};
+
+use breadx::{
+    display::{Display, DisplayFunctionsExt},
+    protocol::shm as xshm,
+    Result,
+};
+use breadx_image::Image;
+
+/// A segment attached to the X11 server that is drawn into through a
+/// private buffer.
+///
+/// Drawing never touches shared memory. The private buffer is only copied
+/// into the segment by `publish`, once the previous put from this segment
+/// has completed. This means the next frame can be drawn while the server
+/// is still reading the last one, without needing a second segment.
+pub struct ShmStagedSegment {
+    /// The private buffer and the segment it is published into.
+    transport: ShmTransport,
+    /// The segment ID used by the server to keep track of the segment.
+    pub(crate) seg_id: xshm::Seg,
+    /// The sequence number of the put the server may still be reading
+    /// the segment for.
+    in_flight: Option<u64>,
+}
+
+pub type ShmStagedImage = Image<ShmStagedSegment>;
+
+impl AsRef<[u8]> for ShmStagedSegment {
+    fn as_ref(&self) -> &[u8] {
+        self.transport.as_ref()
+    }
+}
+
+impl AsMut<[u8]> for ShmStagedSegment {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.transport.as_mut()
+    }
+}
+
+impl Borrow<[u8]> for ShmStagedSegment {
+    fn borrow(&self) -> &[u8] {
+        self.transport.borrow()
+    }
+}
+
+impl BorrowMut<[u8]> for ShmStagedSegment {
+    fn borrow_mut(&mut self) -> &mut [u8] {
+        self.transport.borrow_mut()
+    }
+}
+
+impl Deref for ShmStagedSegment {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.transport.as_ref()
+    }
+}
+
+impl DerefMut for ShmStagedSegment {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.transport.as_mut()
+    }
+}
+
+impl ShmStagedSegment {
+    /// Creates a new staged SHM segment and attaches it to the X11 server.
+    pub fn attach(display: &mut impl Display, len: usize) -> Result<Self> {
+        // first, create the underlying SHM block
+        let transport = ShmTransport::new_read_only(len).map_err(breadx::Error::make_msg)?;
+
+        // now, attach the block to the X11 server
+        let seg_id = display.generate_xid()?;
+        display.shm_attach_checked(seg_id, transport.shm_id() as _, true)?;
+
+        Ok(Self {
+            transport,
+            seg_id,
+            in_flight: None,
+        })
+    }
+
+    /// Detaches the SHM segment from the server.
+    pub fn detach(self, display: &mut impl Display) -> Result<()> {
+        display.shm_detach_checked(self.seg_id)
+    }
+
+    /// Tell whether the server may still be reading from the segment.
+    pub fn is_in_flight(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    /// Get the sequence number of the put the server may still be reading
+    /// the segment for.
+    pub(crate) fn in_flight(&self) -> Option<u64> {
+        self.in_flight
+    }
+
+    /// Mark the segment as being read by the server for the given put.
+    pub(crate) fn set_in_flight(&mut self, seq: u64) {
+        self.in_flight = Some(seq);
+    }
+
+    /// Mark the segment as no longer being read by the server.
+    pub(crate) fn clear_in_flight(&mut self) {
+        self.in_flight = None;
+    }
+
+    /// Handle a completion event, returning `true` if it was meant for
+    /// this segment.
+    ///
+    /// If the event loop receives `ShmCompletion` events itself, they need
+    /// to be passed in here. Otherwise, `shm_publish` will wait for an
+    /// event that has already been consumed.
+    pub fn handle_completion(&mut self, event: &xshm::CompletionEvent) -> bool {
+        if event.shmseg == self.seg_id {
+            self.in_flight = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Publish the private buffer into the segment, unless the server may
+    /// still be reading from it.
+    ///
+    /// Returns `true` if the buffer was published.
+    pub fn try_publish(&mut self) -> bool {
+        self.try_publish_with(|transport| {
+            // SAFETY: the server is not reading from the segment
+            #[allow(unsafe_code)]
+            unsafe {
+                transport.publish()
+            }
+        })
+    }
+
+    /// Publish only the given rectangle of the private buffer, unless the
+    /// server may still be reading from the segment.
+    ///
+    /// Returns `true` if the rectangle was published.
+    pub fn try_publish_rect(
+        &mut self,
+        layout: &ImageLayout,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> bool {
+        self.try_publish_with(|transport| {
+            for range in layout.rect_ranges(x, y, width, height) {
+                // SAFETY: the server is not reading from the segment
+                #[allow(unsafe_code)]
+                unsafe {
+                    transport.publish_range(range)
+                }
+            }
+        })
+    }
+
+    fn try_publish_with(&mut self, f: impl FnOnce(&mut ShmTransport)) -> bool {
+        if self.in_flight.is_some() {
+            return false;
+        }
+
+        f(&mut self.transport);
+        true
+    }
+}
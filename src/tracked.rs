@@ -0,0 +1,207 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use crate::{wait_for_completion, DamageRegion, ShmDisplayExt, ShmImage};
+use std::iter::Extend;
+
+use breadx::{
+    display::Display,
+    protocol::{
+        xproto::{Drawable, Gcontext, Rectangle},
+        Event,
+    },
+    Result,
+};
+
+/// An `ShmImage` that keeps track of which parts of it have been written
+/// to since it was last presented.
+///
+/// Presenting the image only uploads the damaged rectangles, instead of
+/// the entire image.
+pub struct TrackedShmImage {
+    /// The image being tracked.
+    image: ShmImage,
+    /// The parts of the image written to since the last present.
+    damage: DamageRegion,
+}
+
+impl From<ShmImage> for TrackedShmImage {
+    fn from(image: ShmImage) -> Self {
+        Self::new(image)
+    }
+}
+
+impl TrackedShmImage {
+    /// Start tracking the damage done to an image.
+    ///
+    /// The entire image starts out damaged, so the first present uploads
+    /// all of it.
+    pub fn new(image: ShmImage) -> Self {
+        Self::with_damage(image, DamageRegion::default())
+    }
+
+    /// Start tracking the damage done to an image, using the given region
+    /// to store it.
+    pub fn with_damage(image: ShmImage, damage: DamageRegion) -> Self {
+        let mut this = Self { image, damage };
+        this.damage_all();
+        this
+    }
+
+    /// Get the image being tracked.
+    pub fn image(&self) -> &ShmImage {
+        &self.image
+    }
+
+    /// Get the image being tracked, mutably.
+    ///
+    /// Writes made through this reference are not tracked; use
+    /// `damage_rect` to mark them.
+    pub fn image_mut(&mut self) -> &mut ShmImage {
+        &mut self.image
+    }
+
+    /// Stop tracking the image.
+    pub fn into_inner(self) -> ShmImage {
+        self.image
+    }
+
+    /// Get the damaged parts of the image.
+    pub fn damage(&self) -> &DamageRegion {
+        &self.damage
+    }
+
+    /// Mark a rectangle of the image as damaged.
+    ///
+    /// Damage is kept in X11 rectangles, so only the first 32767 columns
+    /// and rows of an image can be marked; the rectangle is clipped to
+    /// those as well as to the image.
+    pub fn damage_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        // clip to the image
+        let limit = i16::MAX as usize;
+        let x_end = x.saturating_add(width).min(self.image.width()).min(limit);
+        let y_end = y.saturating_add(height).min(self.image.height()).min(limit);
+
+        if x < x_end && y < y_end {
+            self.damage.add(Rectangle {
+                x: x as _,
+                y: y as _,
+                width: (x_end - x) as _,
+                height: (y_end - y) as _,
+            });
+        }
+    }
+
+    /// Mark the entire image as damaged.
+    pub fn damage_all(&mut self) {
+        self.damage_rect(0, 0, self.image.width(), self.image.height());
+    }
+
+    /// Set the value of a pixel at the given coordinates.
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: u32) {
+        self.image.set_pixel(x, y, pixel);
+        self.damage_rect(x, y, 1, 1);
+    }
+
+    /// Fill a rectangle of the image with the given pixel.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, pixel: u32) {
+        let x_end = x.saturating_add(width).min(self.image.width());
+        let y_end = y.saturating_add(height).min(self.image.height());
+
+        for y in y..y_end {
+            for x in x..x_end {
+                self.image.set_pixel(x, y, pixel);
+            }
+        }
+
+        self.damage_rect(x, y, width, height);
+    }
+
+    /// Write a run of pixels into a row of the image, starting at the
+    /// given coordinates.
+    pub fn write_row(&mut self, x: usize, y: usize, pixels: &[u32]) {
+        let len = pixels.len().min(self.image.width().saturating_sub(x));
+
+        for (i, pixel) in pixels[..len].iter().enumerate() {
+            self.image.set_pixel(x + i, y, *pixel);
+        }
+
+        self.damage_rect(x, y, len, 1);
+    }
+
+    /// Upload the damaged parts of the image to the server, and wait for
+    /// the server to finish reading them.
+    ///
+    /// One put request is sent per damaged rectangle. The damage is
+    /// cleared once every put has completed, so it is kept if any of them
+    /// fail. Events that are not SHM related are stored in the passed-in
+    /// queue.
+    pub fn present(
+        &mut self,
+        display: &mut impl Display,
+        drawable: impl Into<Drawable>,
+        gc: impl Into<Gcontext>,
+        dest_x: i16,
+        dest_y: i16,
+        queue: &mut impl Extend<Event>,
+    ) -> Result<()> {
+        let drawable = drawable.into();
+        let gc = gc.into();
+        let rects = self.damage.rects().to_vec();
+
+        // work out where every rectangle goes before sending any of them
+        let dests = rects
+            .iter()
+            .map(|rect| {
+                dest_x
+                    .checked_add(rect.x)
+                    .zip(dest_y.checked_add(rect.y))
+                    .ok_or_else(|| breadx::Error::make_msg("destination is out of range"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (i, (rect, (x, y))) in rects.iter().zip(dests).enumerate() {
+            let (src_x, src_y) = (rect.x as u16, rect.y as u16);
+
+            if i + 1 < rects.len() {
+                display.shm_put_ximage_neh(
+                    &mut self.image,
+                    drawable,
+                    gc,
+                    src_x,
+                    src_y,
+                    rect.width,
+                    rect.height,
+                    x,
+                    y,
+                    false,
+                )?;
+            } else {
+                // only the last put needs to tell us that it's done, since
+                // the server handles them in order; it is checked, since a
+                // failed put never sends a completion event
+                display.shm_put_ximage_neh_checked(
+                    &mut self.image,
+                    drawable,
+                    gc,
+                    src_x,
+                    src_y,
+                    rect.width,
+                    rect.height,
+                    x,
+                    y,
+                    true,
+                )?;
+            }
+        }
+
+        if !rects.is_empty() {
+            wait_for_completion(display, self.image.storage().seg_id, queue)?;
+        }
+
+        self.damage.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//               Copyright John Nunley, 2022.
+// Distributed under the Boost Software License, Version 1.0.
+//       (See accompanying file LICENSE or copy at
+//         https://www.boost.org/LICENSE_1_0.txt)
+
+use breadx::protocol::xproto::{Setup, VisualClass, Visualid, Visualtype, Window};
+
+/// A description of how the pixels of a visual are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VisualInfo {
+    /// The ID of the visual.
+    id: Visualid,
+    /// The depth the visual belongs to.
+    depth: u8,
+    /// The class of the visual.
+    class: VisualClass,
+    /// The number of significant bits in each color channel.
+    bits_per_rgb: u8,
+    /// The number of entries in colormaps for the visual.
+    colormap_entries: u16,
+    /// The masks of the red, green and blue channels.
+    masks: [u32; 3],
+}
+
+impl VisualInfo {
+    /// Find the visual with the given ID among the screens of the setup.
+    pub fn find(setup: &Setup, id: Visualid) -> Option<Self> {
+        setup
+            .roots
+            .iter()
+            .flat_map(|screen| screen.allowed_depths.iter())
+            .flat_map(|depth| {
+                depth
+                    .visuals
+                    .iter()
+                    .map(move |visual| (depth.depth, visual))
+            })
+            .find(|(_, visual)| visual.visual_id == id)
+            .map(|(depth, visual)| Self::new(depth, visual))
+    }
+
+    /// Find the visual of the given root window.
+    pub fn root(setup: &Setup, root: Window) -> Option<Self> {
+        let screen = setup.roots.iter().find(|screen| screen.root == root)?;
+        Self::find(setup, screen.root_visual)
+    }
+
+    /// Find the visual that drawables of the given depth use on the screen
+    /// with the given root window.
+    ///
+    /// This is the root visual if the depth matches the root window, and
+    /// the first TrueColor visual of that depth otherwise.
+    pub fn for_depth(setup: &Setup, root: Window, depth: u8) -> Option<Self> {
+        let screen = setup.roots.iter().find(|screen| screen.root == root)?;
+        if screen.root_depth == depth {
+            return Self::find(setup, screen.root_visual);
+        }
+
+        let visuals = &screen
+            .allowed_depths
+            .iter()
+            .find(|allowed| allowed.depth == depth)?
+            .visuals;
+
+        visuals
+            .iter()
+            .find(|visual| visual.class == VisualClass::TRUE_COLOR)
+            .or_else(|| visuals.first())
+            .map(|visual| Self::new(depth, visual))
+    }
+
+    /// Find a 32-bit ARGB visual on the screen with the given root window.
+    pub fn find_argb32(setup: &Setup, root: Window) -> Option<Self> {
+        let screen = setup.roots.iter().find(|screen| screen.root == root)?;
+
+        screen
+            .allowed_depths
+            .iter()
+            .filter(|allowed| allowed.depth == 32)
+            .flat_map(|allowed| allowed.visuals.iter())
+            .map(|visual| Self::new(32, visual))
+            .find(Self::is_argb32)
+    }
+
+    /// Create a description of a visual of the given depth.
+    pub fn new(depth: u8, visual: &Visualtype) -> Self {
+        Self {
+            id: visual.visual_id,
+            depth,
+            class: visual.class,
+            bits_per_rgb: visual.bits_per_rgb_value,
+            colormap_entries: visual.colormap_entries,
+            masks: [visual.red_mask, visual.green_mask, visual.blue_mask],
+        }
+    }
+
+    /// Get the ID of the visual.
+    pub fn id(&self) -> Visualid {
+        self.id
+    }
+
+    /// Get the depth the visual belongs to.
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// Get the class of the visual.
+    pub fn class(&self) -> VisualClass {
+        self.class
+    }
+
+    /// Get the number of significant bits in each color channel.
+    pub fn bits_per_rgb(&self) -> u8 {
+        self.bits_per_rgb
+    }
+
+    /// Get the number of entries in colormaps for the visual.
+    pub fn colormap_entries(&self) -> u16 {
+        self.colormap_entries
+    }
+
+    /// Get the mask of the red channel.
+    pub fn red_mask(&self) -> u32 {
+        self.masks[0]
+    }
+
+    /// Get the mask of the green channel.
+    pub fn green_mask(&self) -> u32 {
+        self.masks[1]
+    }
+
+    /// Get the mask of the blue channel.
+    pub fn blue_mask(&self) -> u32 {
+        self.masks[2]
+    }
+
+    /// Get the mask of the alpha channel.
+    ///
+    /// This covers the bits of the depth that no color channel uses, so it
+    /// is zero for most visuals.
+    pub fn alpha_mask(&self) -> u32 {
+        let depth_mask = match self.depth {
+            32.. => !0,
+            depth => (1u32 << depth) - 1,
+        };
+
+        depth_mask & !(self.masks[0] | self.masks[1] | self.masks[2])
+    }
+
+    /// Get the masks of the red, green and blue channels, in that order.
+    pub fn masks(&self) -> [u32; 3] {
+        self.masks
+    }
+
+    /// Tell whether pixels of this visual hold their colors directly,
+    /// rather than indexing into a colormap.
+    pub fn is_direct(&self) -> bool {
+        self.class == VisualClass::TRUE_COLOR
+    }
+
+    /// Tell whether this is a 32-bit TrueColor visual with eight bits for
+    /// each channel, including alpha.
+    pub fn is_argb32(&self) -> bool {
+        self.is_direct()
+            && self.depth == 32
+            && self.alpha_mask() == 0xFF00_0000
+            && self.masks.iter().all(|mask| mask.count_ones() == 8)
+    }
+}